@@ -0,0 +1,249 @@
+use std::io::{BufRead, IsTerminal};
+use std::path::PathBuf;
+
+use anyhow::{bail, Context};
+use axum_sessions::async_session::SessionStore;
+use chrono::Local;
+use clap::{Parser, Subcommand, ValueEnum};
+
+use crate::configure::{get_config, init_configure, load_configure, Config};
+use crate::database::{get_database, init_database};
+use crate::http::rocksdb_session_store::{is_locked, RocksdbStore};
+use crate::http::session_secret::Secrets;
+use crate::log::init_console_subscriber;
+
+#[derive(Parser, Debug)]
+#[command(version, about = "CatPanel后端")]
+pub struct Cli {
+    /// 数据目录，会覆盖配置中的`storage.data_dir`
+    #[arg(long, global = true)]
+    pub data_dir: Option<PathBuf>,
+    /// 不指定时默认为`serve`
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// 启动面板服务
+    Serve,
+    /// 检查或导出配置
+    #[command(subcommand)]
+    Config(ConfigCommand),
+    /// 管理用户
+    #[command(subcommand)]
+    User(UserCommand),
+    /// 管理session
+    #[command(subcommand)]
+    Sessions(SessionsCommand),
+    /// 管理session cookie的签名密钥
+    #[command(subcommand)]
+    Secret(SecretCommand),
+    /// 执行数据库迁移
+    Migrate,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum ConfigCommand {
+    /// 校验合并后的配置并输出
+    Check,
+    /// 输出合并后的配置
+    Dump {
+        #[arg(long, value_enum, default_value_t = ConfigFormat::Toml)]
+        format: ConfigFormat,
+    },
+    /// 输出每个配置项的值和来源
+    Sources,
+    /// 列出通过webui持久化的配置的历史版本
+    History,
+    /// 恢复到某个历史版本
+    Rollback { id: String },
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+pub enum ConfigFormat {
+    Toml,
+    Json,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum UserCommand {
+    /// 创建用户
+    ///
+    /// 密码在终端中输入，或者通过管道从标准输入读取一行。不接受命令行参数，以免密码出现在进程列表和shell历史中
+    Add { username: String },
+    /// 修改用户密码，同时注销该用户的全部session
+    ///
+    /// 密码的读取方式与`user add`相同
+    Passwd { username: String },
+    /// 删除用户，同时注销该用户的全部session
+    Del { username: String },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum SessionsCommand {
+    /// 删除全部session，所有用户都需要重新登录
+    Clear,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum SecretCommand {
+    /// 轮换签名密钥，旧密钥签名的cookie仍然有效
+    Rotate {
+        /// 同时丢弃旧密钥并删除全部session，所有用户都需要重新登录
+        #[arg(long)]
+        logout_all: bool,
+    },
+}
+
+/// 执行除了`serve`以外的子命令
+pub async fn run(command: Command) -> anyhow::Result<()> {
+    init_console_subscriber();
+    match command {
+        Command::Serve => unreachable!("`serve`由main处理"),
+        Command::Config(ConfigCommand::Check) => {
+            let (config, outcome) = load_configure()?;
+            if let Some(path) = outcome.dotenv {
+                eprintln!("读取{}", path.display());
+            }
+            for issue in outcome.warnings {
+                eprintln!("{}", issue);
+            }
+            eprintln!("配置有效");
+            println!("{}", format_config(&config, ConfigFormat::Toml)?);
+        }
+        Command::Config(ConfigCommand::Dump { format }) => {
+            println!("{}", format_config(&load_configure()?.0, format)?);
+        }
+        Command::Config(ConfigCommand::History) => {
+            init_configure()?;
+            for version in crate::configure::history()? {
+                println!("{}  {}", version.id, version.saved_at.with_timezone(&Local));
+            }
+        }
+        Command::Config(ConfigCommand::Rollback { id }) => {
+            init_configure()?;
+            let outcome = crate::configure::rollback(&id)?;
+            for change in outcome.changes {
+                println!(
+                    "{}: {} -> {}",
+                    change.key,
+                    change.old.unwrap_or_default(),
+                    change.new.unwrap_or_default()
+                );
+            }
+            println!("已恢复到版本{}", id);
+        }
+        Command::Config(ConfigCommand::Sources) => {
+            init_configure()?;
+            for entry in crate::configure::provenance()? {
+                println!("{} = {}  # {}", entry.key, entry.value, entry.source);
+            }
+        }
+        Command::User(command) => {
+            init_configure()?;
+            get_config().storage.create_dirs()?;
+            init_database().await?;
+            run_user(command).await?;
+        }
+        Command::Sessions(SessionsCommand::Clear) => {
+            init_configure()?;
+            RocksdbStore::new()?.clear_store().await?;
+            println!("已删除全部session");
+        }
+        Command::Secret(SecretCommand::Rotate { logout_all }) => {
+            init_configure()?;
+            // 面板正在运行时session数据库被占用无法打开，这时不修改密钥
+            let store = logout_all.then(RocksdbStore::new).transpose()?;
+            let mut secrets = Secrets::load_or_create()?;
+            secrets.rotate(!logout_all);
+            secrets.save()?;
+            if let Some(store) = store {
+                store.clear_store().await?;
+                println!("已轮换session密钥并删除全部session");
+            } else {
+                println!("已轮换session密钥，面板正在运行时需要重启才会使用新的密钥");
+            }
+        }
+        Command::Migrate => {
+            init_configure()?;
+            get_config().storage.create_dirs()?;
+            let applied = crate::database::migrate().await?;
+            if applied.is_empty() {
+                println!("数据库已经是最新的");
+            }
+            for name in applied {
+                println!("已执行迁移: {}", name);
+            }
+        }
+    }
+    Ok(())
+}
+
+async fn run_user(command: UserCommand) -> anyhow::Result<()> {
+    let db = get_database();
+    match command {
+        UserCommand::Add { username } => {
+            let password = read_password()?;
+            let user = crate::user::create_user(db, &username, password).await?;
+            println!("已创建用户{} (id: {})", user.username, user.id);
+        }
+        UserCommand::Passwd { username } => {
+            let password = read_password()?;
+            let user = crate::user::set_password(db, &username, password).await?;
+            println!("已修改用户{}的密码", user.username);
+            revoke_user_sessions(user.id)?;
+        }
+        UserCommand::Del { username } => {
+            let user = crate::user::delete_user(db, &username).await?;
+            println!("已删除用户{}", user.username);
+            revoke_user_sessions(user.id)?;
+        }
+    }
+    Ok(())
+}
+
+fn format_config(config: &Config, format: ConfigFormat) -> anyhow::Result<String> {
+    Ok(match format {
+        ConfigFormat::Toml => toml::to_string_pretty(config)?,
+        ConfigFormat::Json => serde_json::to_string_pretty(config)?,
+    })
+}
+
+/// 在终端中输入时不回显并且需要输入两次，否则从标准输入读取一行
+fn read_password() -> anyhow::Result<String> {
+    let password = if std::io::stdin().is_terminal() {
+        let password = rpassword::prompt_password("密码: ")?;
+        if rpassword::prompt_password("再次输入密码: ")? != password {
+            bail!("两次输入的密码不一致");
+        }
+        password
+    } else {
+        let mut line = String::new();
+        std::io::stdin().lock().read_line(&mut line)?;
+        line.trim_end_matches(['\r', '\n']).to_owned()
+    };
+    if password.is_empty() {
+        bail!("密码不能为空");
+    }
+    Ok(password)
+}
+
+/// 删除该用户的session
+/// 面板正在运行时session数据库被占用无法打开，不过面板每次请求都会检查用户是否存在以及密码是否修改过，
+/// 这些session已经无法再使用，只是要等到过期后才会被删除
+fn revoke_user_sessions(user_id: i32) -> anyhow::Result<()> {
+    let store = match RocksdbStore::new() {
+        Ok(store) => store,
+        Err(err) if is_locked(&err) => {
+            println!("面板正在运行，该用户已经登录的session在下一次请求时失效");
+            return Ok(());
+        }
+        Err(err) => return Err(err).context("打开session数据库失败"),
+    };
+    let count = store
+        .revoke_user_sessions(user_id)
+        .context("删除该用户的session失败")?;
+    println!("已注销{}个session", count);
+    Ok(())
+}
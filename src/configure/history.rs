@@ -0,0 +1,164 @@
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context};
+use chrono::{DateTime, TimeZone, Utc};
+
+use crate::configure::{apply, get_config, MergeOutcome, StorageConfig, RUNTIME_PATCH};
+
+/// 保留的历史版本数量
+const MAX_VERSIONS: usize = 10;
+
+/// `_config_auto.json`的一个历史版本
+#[derive(Debug, Clone)]
+pub struct ConfigVersion {
+    /// 被替换时的毫秒时间戳
+    pub id: String,
+    pub saved_at: DateTime<Utc>,
+    pub path: PathBuf,
+}
+
+/// 读取`_config_auto.json`，不存在时为空对象
+pub(super) fn read_auto_config(storage: &StorageConfig) -> anyhow::Result<serde_json::Value> {
+    let path = storage.auto_config();
+    if !path.exists() {
+        return Ok(serde_json::Value::Object(Default::default()));
+    }
+    serde_json::from_slice(&std::fs::read(&path)?)
+        .with_context(|| format!("{}已损坏", path.display()))
+}
+
+/// 写入`_config_auto.json`，原来的文件会被移动到历史目录
+/// 先写入临时文件再重命名，避免写入一半的时候崩溃导致下次启动时无法读取配置
+pub(super) fn save_auto_config(
+    storage: &StorageConfig,
+    value: &serde_json::Value,
+) -> anyhow::Result<()> {
+    let path = storage.auto_config();
+    let history_dir = storage.config_history();
+    if path.exists() {
+        std::fs::create_dir_all(&history_dir)?;
+        // 同一毫秒内保存多次时顺延到最新版本之后，避免覆盖之前的版本并保持顺序
+        let latest = list(&history_dir)?
+            .first()
+            .map_or(i64::MIN, |version| version.saved_at.timestamp_millis());
+        let millis = Utc::now().timestamp_millis().max(latest + 1);
+        let backup = history_dir.join(format!("{}.json", millis));
+        std::fs::copy(&path, backup).with_context(|| "备份配置文件失败")?;
+    }
+
+    let tmp = path.with_extension("tmp");
+    let mut file = OpenOptions::new()
+        .create(true)
+        .write(true)
+        .truncate(true)
+        .open(&tmp)
+        .with_context(|| format!("写入{}失败", tmp.display()))?;
+    file.write_all(&serde_json::to_vec_pretty(value)?)?;
+    file.sync_all()?;
+    std::fs::rename(&tmp, &path)?;
+    // 重命名本身需要同步所在的目录才能保证落盘
+    if let Some(dir) = path.parent() {
+        File::open(dir)?.sync_all()?;
+    }
+
+    prune(&history_dir)
+}
+
+/// 列出全部历史版本，新的在前
+pub fn history() -> anyhow::Result<Vec<ConfigVersion>> {
+    list(&get_config().storage.config_history())
+}
+
+/// 用某个历史版本替换`_config_auto.json`并丢弃运行时的修改，当前版本同样会进入历史
+pub fn rollback(id: &str) -> anyhow::Result<MergeOutcome> {
+    let version = match history()?.into_iter().find(|version| version.id == id) {
+        Some(version) => version,
+        None => bail!("配置版本{}不存在", id),
+    };
+    let data = std::fs::read(&version.path)?;
+    let value = serde_json::from_slice::<serde_json::Value>(&data)
+        .with_context(|| format!("配置版本{}已损坏", id))?;
+    let mut runtime = RUNTIME_PATCH.lock();
+    let empty = serde_json::Value::Object(Default::default());
+    let outcome = apply(&value, Some(value.clone()), &empty)?;
+    *runtime = empty;
+    Ok(outcome)
+}
+
+fn list(dir: &Path) -> anyhow::Result<Vec<ConfigVersion>> {
+    if !dir.exists() {
+        return Ok(Vec::new());
+    }
+    let mut versions = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        // 只接受由`save_auto_config`生成的文件名，顺便避免id被用来访问其他文件
+        let millis = match path
+            .extension()
+            .filter(|ext| *ext == "json")
+            .and(path.file_stem())
+            .and_then(|stem| stem.to_str())
+            .and_then(|stem| stem.parse::<i64>().ok())
+        {
+            Some(millis) => millis,
+            None => continue,
+        };
+        if let Some(saved_at) = Utc.timestamp_millis_opt(millis).single() {
+            versions.push(ConfigVersion {
+                id: millis.to_string(),
+                saved_at,
+                path,
+            });
+        }
+    }
+    versions.sort_by_key(|version| std::cmp::Reverse(version.saved_at));
+    Ok(versions)
+}
+
+fn prune(dir: &Path) -> anyhow::Result<()> {
+    for version in list(dir)?.into_iter().skip(MAX_VERSIONS) {
+        std::fs::remove_file(version.path)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use crate::configure::history::{list, read_auto_config, save_auto_config, MAX_VERSIONS};
+    use crate::configure::{get_config, init_test_configure, merge_value, Config};
+
+    #[test]
+    fn test_save_auto_config() -> anyhow::Result<()> {
+        let _config = init_test_configure()?;
+        let dir = tempfile::tempdir()?;
+        let mut config = Config::clone(&get_config());
+        config.storage.data_dir = dir.path().to_owned();
+        let storage = &config.storage;
+
+        // 和`merge`一样，每次只把修改合并到原有的内容上
+        for patch in [
+            json!({ "http": { "bind": ["127.0.0.1:1"] } }),
+            json!({ "log": { "level": "debug" } }),
+        ] {
+            let mut auto = read_auto_config(storage)?;
+            merge_value(&mut auto, patch);
+            save_auto_config(storage, &auto)?;
+        }
+        assert_eq!(
+            serde_json::from_slice::<serde_json::Value>(&std::fs::read(storage.auto_config())?)?,
+            json!({ "http": { "bind": ["127.0.0.1:1"] }, "log": { "level": "debug" } })
+        );
+
+        for _ in 0..MAX_VERSIONS + 3 {
+            // 同一毫秒内保存多次也不会覆盖之前的版本
+            save_auto_config(storage, &json!({}))?;
+        }
+        assert_eq!(list(&storage.config_history())?.len(), MAX_VERSIONS);
+        assert!(!storage.auto_config().with_extension("tmp").exists());
+        Ok(())
+    }
+}
@@ -0,0 +1,145 @@
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use notify::{RecommendedWatcher, RecursiveMode, Watcher};
+use tokio::sync::{mpsc, watch};
+use tokio_graceful_shutdown::SubsystemHandle;
+use tracing::{error, info};
+
+use crate::configure::{get_config, on_config_change, reload_configure, CONFIG_FILE};
+
+/// 编辑器保存文件时往往会产生好几个事件，等待一段时间后合并成一次
+const DEBOUNCE: Duration = Duration::from_millis(200);
+
+/// 监听一组文件的变化
+/// 监听的是文件所在的目录而不是文件本身，这样文件被删除或者被替换(很多编辑器保存的方式)之后仍然有效
+pub struct FileWatcher {
+    _watcher: RecommendedWatcher,
+    rx: mpsc::Receiver<()>,
+}
+
+impl FileWatcher {
+    pub fn new(files: &[PathBuf]) -> anyhow::Result<Self> {
+        let (tx, rx) = mpsc::channel(1);
+        let targets = files
+            .iter()
+            .filter_map(|file| watch_target(file))
+            .collect::<Vec<_>>();
+
+        let watched = targets.clone();
+        let mut watcher =
+            notify::recommended_watcher(move |res: notify::Result<notify::Event>| {
+                if let Ok(event) = res {
+                    if event.paths.iter().any(|path| {
+                        watched.iter().any(|(dir, name)| {
+                            path.parent() == Some(dir) && path.file_name() == Some(name.as_os_str())
+                        })
+                    }) {
+                        // 已经有未处理的通知时直接忽略
+                        tx.try_send(()).ok();
+                    }
+                }
+            })?;
+        watch_dirs(&mut watcher, &targets)?;
+
+        Ok(FileWatcher {
+            _watcher: watcher,
+            rx,
+        })
+    }
+
+    /// 等待任意一个文件发生变化，短时间内的多次变化会被合并
+    pub async fn changed(&mut self) {
+        if self.rx.recv().await.is_none() {
+            // watcher还活着的时候发送端不会被drop
+            futures::future::pending::<()>().await;
+        }
+        tokio::time::sleep(DEBOUNCE).await;
+        while self.rx.try_recv().is_ok() {}
+    }
+}
+
+/// 监听配置文件的变化和SIGHUP信号，重新加载配置
+/// 新配置无效时只记录错误，继续使用原来的配置
+/// `storage.data_dir`修改后改为监听新的数据目录中的`_config_auto.json`
+pub async fn watch_config(handle: SubsystemHandle) -> anyhow::Result<()> {
+    let (auto_config_tx, mut auto_config) = watch::channel(get_config().storage.auto_config());
+    let mut watcher = FileWatcher::new(&[
+        PathBuf::from(CONFIG_FILE),
+        auto_config.borrow_and_update().clone(),
+    ])?;
+    on_config_change(move |config| {
+        let path = config.storage.auto_config();
+        auto_config_tx.send_if_modified(|watched| {
+            let changed = *watched != path;
+            *watched = path;
+            changed
+        });
+        !auto_config_tx.is_closed()
+    });
+
+    #[cfg(unix)]
+    let mut hangup = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())?;
+
+    loop {
+        #[cfg(unix)]
+        let sighup = hangup.recv();
+        #[cfg(not(unix))]
+        let sighup = futures::future::pending::<Option<()>>();
+
+        tokio::select! {
+            _ = handle.on_shutdown_requested() => break,
+            _ = watcher.changed() => reload("配置文件发生变化").await,
+            _ = sighup => reload("收到SIGHUP").await,
+            Ok(()) = auto_config.changed() => {
+                let path = auto_config.borrow_and_update().clone();
+                match FileWatcher::new(&[PathBuf::from(CONFIG_FILE), path.clone()]) {
+                    Ok(new) => {
+                        watcher = new;
+                        info!("数据目录发生变化，改为监听{}", path.display());
+                    }
+                    Err(err) => error!("监听{}失败，继续监听原来的文件: {:#}", path.display(), err),
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+async fn reload(reason: &str) {
+    // 重新加载时需要等待`merge`释放锁并读取文件，不阻塞运行时的线程
+    match tokio::task::spawn_blocking(reload_configure)
+        .await
+        .map_err(anyhow::Error::from)
+        .and_then(|result| result)
+    {
+        Ok(()) => info!("{}，已重新加载配置", reason),
+        Err(err) => error!(
+            "{}，但是重新加载配置失败，继续使用原来的配置: {:#}",
+            reason, err
+        ),
+    }
+}
+
+/// 返回文件所在目录的绝对路径和文件名
+fn watch_target(file: &Path) -> Option<(PathBuf, PathBuf)> {
+    let name = PathBuf::from(file.file_name()?);
+    let dir = match file.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        Some(dir) => dir,
+        None => Path::new("."),
+    };
+    Some((dir.canonicalize().ok()?, name))
+}
+
+fn watch_dirs(
+    watcher: &mut RecommendedWatcher,
+    targets: &[(PathBuf, PathBuf)],
+) -> anyhow::Result<()> {
+    let mut dirs = targets.iter().map(|(dir, _)| dir).collect::<Vec<_>>();
+    dirs.sort();
+    dirs.dedup();
+    for dir in dirs {
+        watcher.watch(dir, RecursiveMode::NonRecursive)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,784 @@
+use std::collections::BTreeMap;
+use std::fmt::{Display, Formatter};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use arc_swap::{ArcSwap, Guard};
+use byte_unit::Byte;
+use figment::providers::{Env, Format, Json, Serialized, Toml};
+use figment::Figment;
+use ipnet::IpNet;
+use once_cell::sync::{Lazy, OnceCell};
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use tokio::sync::watch;
+use tracing::warn;
+
+pub use crate::configure::history::{history, rollback, ConfigVersion};
+pub use crate::configure::hot_reload::{watch_config, FileWatcher};
+use crate::configure::provenance::ConfigLayer;
+pub use crate::configure::provenance::{provenance, ConfigSource, Provenance};
+pub use crate::configure::validate::{ConfigError, Issue};
+
+mod history;
+mod hot_reload;
+mod provenance;
+mod validate;
+
+/// 用户编辑的配置文件
+const CONFIG_FILE: &str = "config.toml";
+
+/// 修改后需要重启才能生效的配置项
+const RESTART_REQUIRED: &[&str] = &[
+    "http.bind",
+    "http.unix_socket_mode",
+    "http.session_idle_timeout",
+    "http.session_gc_interval",
+    "http.tls",
+    "log.queue_capacity",
+    "log.tail_buffer",
+    "storage",
+];
+
+static CONFIG: OnceCell<ArcSwap<Config>> = OnceCell::new();
+/// 配置被替换时通知订阅者
+static CONFIG_CHANGED: Lazy<watch::Sender<()>> = Lazy::new(|| watch::channel(()).0);
+/// 命令行指定的数据目录，优先级高于所有配置文件和环境变量
+static DATA_DIR_OVERRIDE: OnceCell<PathBuf> = OnceCell::new();
+/// 通过`merge`修改但是没有持久化的配置，重新加载配置时作为单独的一层保留，重启后丢失
+/// 同时用于保证同一时间只有一个`merge`
+static RUNTIME_PATCH: Lazy<Mutex<serde_json::Value>> =
+    Lazy::new(|| Mutex::new(serde_json::Value::Object(Default::default())));
+
+/// 使用命令行参数覆盖数据目录，需要在`init_configure`之前调用
+pub fn override_data_dir(path: PathBuf) {
+    DATA_DIR_OVERRIDE.set(path).ok();
+}
+
+/// `init_configure`时日志还没有初始化，这些信息需要由调用方在初始化日志后输出
+pub struct InitOutcome {
+    /// 读取到环境变量中的`.env`文件
+    pub dotenv: Option<PathBuf>,
+    /// 配置中的警告
+    pub warnings: Vec<Issue>,
+}
+
+pub fn init_configure() -> anyhow::Result<InitOutcome> {
+    let (config, outcome) = load_configure()?;
+    // 在跑tests的时候可能会有多个test用到config，所以简单的无视掉重复初始化
+    // 但是在正式运行的时候不应该发生这种情况
+    #[cfg(test)]
+    CONFIG.set(ArcSwap::from_pointee(config)).ok();
+    #[cfg(not(test))]
+    CONFIG
+        .set(ArcSwap::from_pointee(config))
+        .expect("CONFIG意外的重复初始化");
+    Ok(outcome)
+}
+
+/// 和`init_configure`一样读取`.env`文件和全部配置来源，但是不替换全局配置
+/// 用于只需要查看配置的子命令
+pub fn load_configure() -> anyhow::Result<(Config, InitOutcome)> {
+    load_configure_with(dotenv::dotenv)
+}
+
+/// `dotenv`负责把`.env`文件读取到环境变量中并返回它的路径
+fn load_configure_with(
+    dotenv: impl FnOnce() -> dotenv::Result<PathBuf>,
+) -> anyhow::Result<(Config, InitOutcome)> {
+    // 读取.env文件到环境变量
+    let dotenv = dotenv().ok();
+    let (config, warnings) = load()?;
+    Ok((config, InitOutcome { dotenv, warnings }))
+}
+
+/// 修改全局配置的test持有写锁，其他用到全局配置的test持有读锁
+/// 使用tokio的锁，guard可以跨越异步test中的await
+#[cfg(test)]
+static TEST_CONFIG_LOCK: tokio::sync::RwLock<()> = tokio::sync::RwLock::const_new(());
+
+/// 在test中代替`init_configure`，返回的guard存在期间全局配置不会被其他test修改
+/// 同步和异步的test都可以调用，所以轮询而不是等待锁
+#[cfg(test)]
+pub fn init_test_configure() -> anyhow::Result<tokio::sync::RwLockReadGuard<'static, ()>> {
+    let guard = loop {
+        match TEST_CONFIG_LOCK.try_read() {
+            Ok(guard) => break guard,
+            Err(_) => std::thread::sleep(std::time::Duration::from_millis(1)),
+        }
+    };
+    init_configure()?;
+    Ok(guard)
+}
+
+#[inline]
+pub fn get_config() -> Guard<Arc<Config>> {
+    // SAFETY: 在程序一开始就应该已经调用`init_configure`
+    unsafe { CONFIG.get_unchecked() }.load()
+}
+
+/// 替换当前配置并通知订阅者
+fn store_config(config: Config) {
+    // SAFETY: 在程序一开始就应该已经调用`init_configure`
+    unsafe { CONFIG.get_unchecked() }.store(Arc::new(config));
+    CONFIG_CHANGED.send_replace(());
+}
+
+/// 每次配置被替换后调用`f`，`f`返回false时取消订阅
+/// 需要在tokio运行时中调用
+pub fn on_config_change(mut f: impl FnMut(&Config) -> bool + Send + 'static) {
+    let mut rx = CONFIG_CHANGED.subscribe();
+    tokio::spawn(async move {
+        while rx.changed().await.is_ok() {
+            if !f(&get_config()) {
+                break;
+            }
+        }
+    });
+}
+
+/// 重新读取全部配置来源并替换当前配置
+/// 读取或校验失败时返回错误，当前配置保持不变
+pub fn reload_configure() -> anyhow::Result<()> {
+    // 和`merge`使用同一把锁，否则同时进行的`merge`可能会被基于旧运行时修改的配置覆盖
+    let runtime = RUNTIME_PATCH.lock();
+    let (config, warnings) = load_with(&runtime)?;
+    for issue in warnings {
+        warn!("{}", issue);
+    }
+    for key in restart_required(&get_config(), &config)? {
+        warn!("配置项{}的修改需要重启才能生效", key);
+    }
+    store_config(config);
+    Ok(())
+}
+
+/// 返回`old`和`new`之间有变化并且需要重启才能生效的配置项
+pub fn restart_required(old: &Config, new: &Config) -> anyhow::Result<Vec<&'static str>> {
+    let (old, new) = (serde_json::to_value(old)?, serde_json::to_value(new)?);
+    Ok(RESTART_REQUIRED
+        .iter()
+        .copied()
+        .filter(|key| {
+            let pointer = format!("/{}", key.replace('.', "/"));
+            old.pointer(&pointer) != new.pointer(&pointer)
+        })
+        .collect())
+}
+
+/// 从以下途径读取配置文件(如果有的话)
+/// 后加载的会覆盖先加载的
+fn figment() -> anyhow::Result<Figment> {
+    figment_with(None, &RUNTIME_PATCH.lock())
+}
+
+/// `auto`不为`None`时代替`_config_auto.json`的内容，`runtime`为没有持久化的修改
+fn figment_with(
+    auto: Option<&serde_json::Value>,
+    runtime: &serde_json::Value,
+) -> anyhow::Result<Figment> {
+    let overrides = |figment: Figment| {
+        let figment = figment
+            .merge(ConfigLayer::ConfigFile.wrap(Toml::file(CONFIG_FILE)))
+            // 兼容旧的日志等级环境变量
+            .merge(
+                ConfigLayer::LegacyEnv.wrap(Env::raw().only(&["LOG_LEVEL", "LOG_FILE_LEVEL"]).map(
+                    |key| {
+                        if key == "LOG_LEVEL" {
+                            "log.level".into()
+                        } else {
+                            "log.file_level".into()
+                        }
+                    },
+                )),
+            )
+            // 使用`__`分隔嵌套的key，例如`CP_STORAGE__DATA_DIR`
+            .merge(ConfigLayer::Env.wrap(Env::prefixed("CP_").split("__")));
+        match DATA_DIR_OVERRIDE.get() {
+            Some(path) => figment.merge(
+                ConfigLayer::CommandLine.wrap(Serialized::default("storage.data_dir", path)),
+            ),
+            None => figment,
+        }
+    };
+    // 一个默认的配置文件，里面应该包含所有配置项合理的默认值
+    let defaults = || {
+        Figment::new().merge(ConfigLayer::Default.wrap(Toml::string(include_str!("default.toml"))))
+    };
+
+    // `_config_auto.json`本身位于数据目录下，所以先在不包含它的情况下确定它的路径
+    let storage = overrides(defaults())
+        .extract_inner::<StorageConfig>("storage")
+        .map_err(ConfigError::from)?;
+    let figment = match auto {
+        Some(auto) => defaults().merge(ConfigLayer::AutoConfig.wrap(Serialized::defaults(auto))),
+        None => defaults().merge(ConfigLayer::AutoConfig.wrap(Json::file(storage.auto_config()))),
+    };
+    Ok(overrides(figment.merge(
+        ConfigLayer::Runtime.wrap(Serialized::defaults(runtime)),
+    )))
+}
+
+/// 读取并合并全部配置来源，不会影响当前正在使用的配置
+/// 配置有错误时返回包含全部问题的`ConfigError`，否则返回配置和其中的警告
+pub fn load() -> anyhow::Result<(Config, Vec<Issue>)> {
+    load_with(&RUNTIME_PATCH.lock())
+}
+
+/// 使用给定的运行时修改代替`RUNTIME_PATCH`读取配置，已经持有锁的调用方使用
+fn load_with(runtime: &serde_json::Value) -> anyhow::Result<(Config, Vec<Issue>)> {
+    let figment = figment_with(None, runtime)?;
+    let config = figment.extract::<Config>().map_err(ConfigError::from)?;
+    let warnings = validate::into_result(validate::validate_loaded(&config, &figment)?)?;
+    Ok((config, warnings))
+}
+
+/// 将`target`合并到当前配置中，以达到运行从webui界面更改配置的效果
+/// `persistence`为true时只把`target`合并到`_config_auto.json`中，其他来源的配置不会被写入
+/// 否则作为运行时的修改保留到重启，重新加载配置时不会丢失
+/// 配置文件、环境变量和命令行参数的优先级更高，被它们覆盖的配置项会返回警告
+pub fn merge<T>(target: T, persistence: bool) -> anyhow::Result<MergeOutcome>
+where
+    T: Serialize,
+{
+    let patch = serde_json::to_value(target)?;
+    let mut runtime = RUNTIME_PATCH.lock();
+    let mut new_runtime = runtime.clone();
+    let auto = if persistence {
+        // 持久化的配置项不再需要保留运行时的修改，否则会覆盖`_config_auto.json`里的值
+        remove_keys(&mut new_runtime, &patch);
+        let mut auto = history::read_auto_config(&get_config().storage)?;
+        merge_value(&mut auto, patch.clone());
+        Some(auto)
+    } else {
+        merge_value(&mut new_runtime, patch.clone());
+        None
+    };
+    let outcome = apply(&patch, auto, &new_runtime)?;
+    *runtime = new_runtime;
+    Ok(outcome)
+}
+
+/// 使用新的`_config_auto.json`和运行时修改重新读取配置，检查`patch`并替换当前配置
+/// 调用方需要持有`RUNTIME_PATCH`的锁
+fn apply(
+    patch: &serde_json::Value,
+    auto: Option<serde_json::Value>,
+    runtime: &serde_json::Value,
+) -> anyhow::Result<MergeOutcome> {
+    let old = get_config();
+    let figment = figment_with(auto.as_ref(), runtime)?;
+    let config = figment.extract::<Config>().map_err(ConfigError::from)?;
+    let mut warnings = validate::into_result(validate::validate_patch(&config, patch)?)?;
+    warnings.extend(validate::overridden(&figment, patch));
+    let outcome = MergeOutcome {
+        changes: diff(&old, &config)?,
+        restart_required: restart_required(&old, &config)?,
+        warnings,
+    };
+
+    if let Some(auto) = &auto {
+        history::save_auto_config(&old.storage, auto)?;
+    }
+
+    store_config(config);
+
+    Ok(outcome)
+}
+
+/// `merge`的结果
+#[derive(Debug)]
+pub struct MergeOutcome {
+    pub changes: Vec<ConfigChange>,
+    /// 有变化并且需要重启才能生效的配置项
+    pub restart_required: Vec<&'static str>,
+    pub warnings: Vec<Issue>,
+}
+
+/// 一个配置项的变化，`key`为以`.`分隔的完整路径
+#[derive(Debug, PartialEq)]
+pub struct ConfigChange {
+    pub key: String,
+    pub old: Option<serde_json::Value>,
+    pub new: Option<serde_json::Value>,
+}
+
+/// 对比两个配置，返回全部有变化的配置项
+pub fn diff(old: &Config, new: &Config) -> anyhow::Result<Vec<ConfigChange>> {
+    let mut old_values = BTreeMap::new();
+    flatten(String::new(), serde_json::to_value(old)?, &mut old_values);
+    let mut new_values = BTreeMap::new();
+    flatten(String::new(), serde_json::to_value(new)?, &mut new_values);
+
+    let mut keys = old_values
+        .keys()
+        .chain(new_values.keys())
+        .collect::<Vec<_>>();
+    keys.sort();
+    keys.dedup();
+    Ok(keys
+        .into_iter()
+        .filter(|key| old_values.get(*key) != new_values.get(*key))
+        .map(|key| ConfigChange {
+            key: key.clone(),
+            old: old_values.get(key).cloned(),
+            new: new_values.get(key).cloned(),
+        })
+        .collect())
+}
+
+/// 把`patch`递归地合并到`base`中，对象以外的值直接替换
+fn merge_value(base: &mut serde_json::Value, patch: serde_json::Value) {
+    match (base, patch) {
+        (serde_json::Value::Object(base), serde_json::Value::Object(patch)) => {
+            for (key, value) in patch {
+                merge_value(base.entry(key).or_insert(serde_json::Value::Null), value);
+            }
+        }
+        (base, patch) => *base = patch,
+    }
+}
+
+/// 从`base`中删除`patch`里的全部配置项
+fn remove_keys(base: &mut serde_json::Value, patch: &serde_json::Value) {
+    if let (serde_json::Value::Object(base), serde_json::Value::Object(patch)) = (base, patch) {
+        for (key, value) in patch {
+            match base.get_mut(key) {
+                Some(child) if child.is_object() && value.is_object() => {
+                    remove_keys(child, value);
+                    if child.as_object().is_some_and(|child| child.is_empty()) {
+                        base.remove(key);
+                    }
+                }
+                _ => {
+                    base.remove(key);
+                }
+            }
+        }
+    }
+}
+
+/// 把嵌套的对象展开为`a.b.c`形式的key
+fn flatten(
+    prefix: String,
+    value: serde_json::Value,
+    out: &mut BTreeMap<String, serde_json::Value>,
+) {
+    match value {
+        serde_json::Value::Object(map) => {
+            for (key, value) in map {
+                let key = if prefix.is_empty() {
+                    key
+                } else {
+                    format!("{}.{}", prefix, key)
+                };
+                flatten(key, value, out);
+            }
+        }
+        value => {
+            out.insert(prefix, value);
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Config {
+    pub http: HttpConfig,
+    pub storage: StorageConfig,
+    pub log: LogConfig,
+}
+
+/// 各种持久化数据的存放位置
+/// 除了`data_dir`以外的路径为相对路径时相对于`data_dir`，为绝对路径时直接使用
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct StorageConfig {
+    pub data_dir: PathBuf,
+    sessions: PathBuf,
+    logs: PathBuf,
+    database: PathBuf,
+    auto_config: PathBuf,
+    config_history: PathBuf,
+    session_secret: PathBuf,
+}
+
+impl StorageConfig {
+    /// session数据库(rocksdb)目录
+    pub fn sessions(&self) -> PathBuf {
+        self.data_dir.join(&self.sessions)
+    }
+
+    /// 日志目录
+    pub fn logs(&self) -> PathBuf {
+        self.data_dir.join(&self.logs)
+    }
+
+    /// sqlite数据库文件
+    pub fn database(&self) -> PathBuf {
+        self.data_dir.join(&self.database)
+    }
+
+    /// 从webui修改的配置
+    pub fn auto_config(&self) -> PathBuf {
+        self.data_dir.join(&self.auto_config)
+    }
+
+    /// `_config_auto.json`的历史版本
+    pub fn config_history(&self) -> PathBuf {
+        self.data_dir.join(&self.config_history)
+    }
+
+    /// session cookie的签名密钥
+    pub fn session_secret(&self) -> PathBuf {
+        self.data_dir.join(&self.session_secret)
+    }
+
+    /// 把相对路径解析为相对于`data_dir`的路径
+    pub fn resolve(&self, path: &Path) -> PathBuf {
+        self.data_dir.join(path)
+    }
+
+    /// 创建全部需要的目录
+    pub fn create_dirs(&self) -> anyhow::Result<()> {
+        std::fs::create_dir_all(&self.data_dir)?;
+        std::fs::create_dir_all(self.logs())?;
+        std::fs::create_dir_all(self.config_history())?;
+        for file in [
+            self.sessions(),
+            self.database(),
+            self.auto_config(),
+            self.session_secret(),
+        ] {
+            if let Some(parent) = file.parent().filter(|p| !p.as_os_str().is_empty()) {
+                std::fs::create_dir_all(parent)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct HttpConfig {
+    /// 监听的地址，可以是一个或者多个
+    #[serde(with = "listen_addrs")]
+    pub bind: Vec<ListenAddr>,
+    /// Unix domain socket文件的权限
+    #[serde(with = "file_mode")]
+    pub unix_socket_mode: u32,
+    #[serde(with = "humantime_serde")]
+    pub system_info_refresh_limit: Duration,
+    /// 从登录开始计算的session最长有效期
+    #[serde(with = "humantime_serde")]
+    pub session_ttl: Duration,
+    /// session闲置超过这个时间就会过期
+    #[serde(with = "humantime_serde")]
+    pub session_idle_timeout: Duration,
+    /// 清理过期session的间隔
+    #[serde(with = "humantime_serde")]
+    pub session_gc_interval: Duration,
+    /// 受信任的反向代理，只有来自这些地址的请求才会使用`Forwarded`和`X-Forwarded-For`中的客户端ip
+    pub trusted_proxies: Vec<IpNet>,
+    pub tls: TlsConfig,
+    pub rate_limit: RateLimitConfig,
+}
+
+impl HttpConfig {
+    /// 全部TCP监听地址
+    pub fn tcp_binds(&self) -> impl Iterator<Item = SocketAddr> + '_ {
+        self.bind.iter().filter_map(|addr| match addr {
+            ListenAddr::Tcp(addr) => Some(*addr),
+            ListenAddr::Unix(_) => None,
+        })
+    }
+}
+
+/// 监听地址，`unix:`开头的是Unix domain socket的路径，相对路径时相对于`data_dir`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ListenAddr {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl FromStr for ListenAddr {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.strip_prefix("unix:") {
+            Some("") => anyhow::bail!("Unix domain socket的路径不能为空"),
+            Some(path) => Ok(ListenAddr::Unix(path.into())),
+            None => s
+                .parse()
+                .map(ListenAddr::Tcp)
+                .map_err(|_| anyhow::anyhow!("无效的监听地址: {}", s)),
+        }
+    }
+}
+
+impl Display for ListenAddr {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ListenAddr::Tcp(addr) => write!(f, "{}", addr),
+            ListenAddr::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TlsConfig {
+    pub enabled: bool,
+    /// PEM格式的证书链，相对路径时相对于`data_dir`
+    pub cert: PathBuf,
+    /// PEM格式的私钥，相对路径时相对于`data_dir`
+    pub key: PathBuf,
+    /// 证书和私钥都不存在时自动生成自签名证书
+    pub self_signed: bool,
+    /// 在这个地址上把HTTP请求重定向到HTTPS
+    pub redirect_bind: Option<SocketAddr>,
+}
+
+/// 按客户端ip和用户名限制请求速率，防止暴力破解密码
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RateLimitConfig {
+    pub enabled: bool,
+    /// 每个客户端ip每秒可以发起的请求数
+    pub requests_per_second: f64,
+    /// 允许的突发请求数
+    pub burst: u32,
+    /// 每分钟可以尝试登录的次数，分别按客户端ip以及用户名和客户端ip的组合计算
+    pub login_per_minute: f64,
+    pub login_burst: u32,
+    /// 连续登录失败这么多次后开始锁定客户端ip以及这个ip上的用户名
+    pub lockout_threshold: u32,
+    /// 第一次锁定的时间，之后每次失败翻倍
+    #[serde(with = "humantime_serde")]
+    pub lockout_duration: Duration,
+    /// 锁定时间的上限，超过这个时间没有失败的记录会被清除
+    #[serde(with = "humantime_serde")]
+    pub lockout_max_duration: Duration,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct LogConfig {
+    /// 控制台日志的过滤规则，格式与`RUST_LOG`相同，例如`info,sea_orm=warn`
+    #[serde(deserialize_with = "env_filter::deserialize")]
+    pub level: String,
+    /// 日志文件的过滤规则
+    #[serde(deserialize_with = "env_filter::deserialize")]
+    pub file_level: String,
+    /// 单个日志文件的最大大小，超过后依次写入`日期.1.log`、`日期.2.log`
+    pub max_file_size: Byte,
+    /// 最后一次写入超过这个时间的日志文件会被删除，为0时不限制
+    #[serde(with = "humantime_serde")]
+    pub max_age: Duration,
+    /// 日志目录的总大小，超过后从最旧的文件开始删除，为0时不限制
+    pub max_total_size: Byte,
+    /// 使用zstd压缩已经写完的日志文件
+    pub compress: bool,
+    /// 实时日志保留的最近日志条数，新的订阅者会先收到这些日志
+    pub tail_buffer: usize,
+    /// 每个日志文件等待写入的最大行数
+    pub queue_capacity: usize,
+    /// 等待写入的日志超过`queue_capacity`时的处理方式
+    pub overflow: OverflowPolicy,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum OverflowPolicy {
+    /// 等待日志写入，写入跟不上时会拖慢输出日志的线程
+    /// 多线程运行时的工作线程通过`block_in_place`等待，同一个线程上的其他任务会交给别的线程
+    /// 单线程运行时中等待会拖住全部异步任务，所以这时仍然丢弃并计数
+    Block,
+    /// 丢弃新的日志并计数
+    Drop,
+}
+
+/// 读取配置时检查`EnvFilter`的规则，重新加载时才不会因为规则错误而失败
+mod env_filter {
+    use serde::{Deserialize, Deserializer};
+    use tracing_subscriber::EnvFilter;
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<String, D::Error> {
+        let s = String::deserialize(d)?;
+        EnvFilter::builder()
+            .parse(&s)
+            .map_err(serde::de::Error::custom)?;
+        Ok(s)
+    }
+}
+
+/// 兼容只有一个地址的旧配置`bind = "127.0.0.1:8686"`
+mod listen_addrs {
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    use crate::configure::ListenAddr;
+
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(String),
+        Many(Vec<String>),
+    }
+
+    pub fn serialize<S: Serializer>(addrs: &[ListenAddr], s: S) -> Result<S::Ok, S::Error> {
+        s.collect_seq(addrs.iter().map(ToString::to_string))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Vec<ListenAddr>, D::Error> {
+        let addrs = match OneOrMany::deserialize(d)? {
+            OneOrMany::One(addr) => vec![addr],
+            OneOrMany::Many(addrs) => addrs,
+        };
+        addrs
+            .iter()
+            .map(|addr| addr.parse().map_err(serde::de::Error::custom))
+            .collect()
+    }
+}
+
+/// 文件权限使用八进制字符串表示，例如`"660"`
+mod file_mode {
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(mode: &u32, s: S) -> Result<S::Ok, S::Error> {
+        s.collect_str(&format_args!("{:o}", mode))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<u32, D::Error> {
+        let s = String::deserialize(d)?;
+        match u32::from_str_radix(s.trim_start_matches("0o"), 8) {
+            Ok(mode) if mode <= 0o777 => Ok(mode),
+            _ => Err(serde::de::Error::custom(format!("无效的文件权限: {}", s))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use anyhow::Ok;
+    use serde_json::json;
+
+    use crate::configure::{
+        diff, get_config, init_configure, init_test_configure, load_configure_with, merge,
+        merge_value, reload_configure, remove_keys, restart_required, store_config, Config,
+        ConfigChange, ListenAddr, RUNTIME_PATCH, TEST_CONFIG_LOCK,
+    };
+
+    #[test]
+    fn test_merge() -> anyhow::Result<()> {
+        let _lock = TEST_CONFIG_LOCK.blocking_write();
+        init_configure()?;
+        let original = Config::clone(&get_config());
+        assert_eq!(get_config().http.tcp_binds().next().unwrap().port(), 8686);
+
+        let outcome = merge(
+            json!({
+                "http": {
+                    "bind": "127.0.0.0:65535",
+                },
+            }),
+            false,
+        )?;
+        assert_eq!(
+            get_config().http.bind,
+            [ListenAddr::Tcp("127.0.0.0:65535".parse()?)]
+        );
+        assert_eq!(outcome.restart_required, ["http.bind"]);
+        assert_eq!(
+            outcome.changes,
+            [ConfigChange {
+                key: "http.bind".into(),
+                old: Some(json!(["127.0.0.1:8686"])),
+                new: Some(json!(["127.0.0.0:65535"])),
+            }]
+        );
+
+        // 没有持久化的修改在重新加载配置后仍然有效
+        reload_configure()?;
+        assert_eq!(get_config().http.tcp_binds().next().unwrap().port(), 65535);
+
+        // 类型错误的配置应该被拒绝
+        assert!(merge(json!({ "http": { "bind": 1 } }), false).is_err());
+        assert!(merge(json!({ "http": { "bind": ["unix:"] } }), false).is_err());
+        assert!(merge(json!({ "log": { "level": "sea_orm=loud" } }), false).is_err());
+        assert_eq!(get_config().http.tcp_binds().next().unwrap().port(), 65535);
+
+        merge(
+            json!({ "http": { "bind": ["127.0.0.1:8686", "unix:run/panel.sock"] } }),
+            false,
+        )?;
+        assert_eq!(
+            get_config().http.bind,
+            [
+                ListenAddr::Tcp("127.0.0.1:8686".parse()?),
+                ListenAddr::Unix("run/panel.sock".into())
+            ]
+        );
+        assert!(diff(&get_config(), &get_config())?.is_empty());
+
+        // 恢复原来的配置，避免影响其他test
+        *RUNTIME_PATCH.lock() = json!({});
+        store_config(original);
+        Ok(())
+    }
+
+    #[test]
+    fn test_merge_value() {
+        let mut base = json!({ "http": { "bind": ["127.0.0.1:1"], "cors": true }, "log": { "level": "info" } });
+        merge_value(
+            &mut base,
+            json!({ "http": { "bind": ["127.0.0.1:2"] }, "storage": { "data_dir": "data" } }),
+        );
+        assert_eq!(
+            base,
+            json!({
+                "http": { "bind": ["127.0.0.1:2"], "cors": true },
+                "log": { "level": "info" },
+                "storage": { "data_dir": "data" },
+            })
+        );
+
+        remove_keys(
+            &mut base,
+            &json!({ "http": { "bind": [] }, "log": { "level": "debug" } }),
+        );
+        assert_eq!(
+            base,
+            json!({ "http": { "cors": true }, "storage": { "data_dir": "data" } })
+        );
+    }
+
+    #[test]
+    fn test_restart_required() -> anyhow::Result<()> {
+        let _config = init_test_configure()?;
+        let old = Config::clone(&get_config());
+        let mut new = old.clone();
+        new.http.system_info_refresh_limit = Duration::from_secs(5);
+        assert!(restart_required(&old, &new)?.is_empty());
+
+        new.http.bind = vec!["0.0.0.0:1".parse()?];
+        new.storage.data_dir = "data".into();
+        assert_eq!(restart_required(&old, &new)?, ["http.bind", "storage"]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_load_dotenv() -> anyhow::Result<()> {
+        // 环境变量是全局的，会影响其他test读取到的配置
+        let _lock = TEST_CONFIG_LOCK.blocking_write();
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join(".env");
+        std::fs::write(&path, "CP_HTTP__SESSION_TTL=3d\n")?;
+
+        let result = load_configure_with(|| dotenv::from_path(&path).map(|_| path.clone()));
+        std::env::remove_var("CP_HTTP__SESSION_TTL");
+        let (config, outcome) = result?;
+        assert_eq!(outcome.dotenv, Some(path));
+        assert_eq!(config.http.session_ttl, Duration::from_secs(3 * 24 * 3600));
+        Ok(())
+    }
+}
@@ -0,0 +1,207 @@
+use std::collections::BTreeMap;
+use std::fmt::{Display, Formatter};
+use std::path::PathBuf;
+
+use figment::value::{Dict, Map};
+use figment::{Figment, Metadata, Profile, Provider};
+
+use crate::configure::{figment, flatten, get_config, Config};
+
+/// 配置的来源，后面的会覆盖前面的
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(super) enum ConfigLayer {
+    Default,
+    AutoConfig,
+    /// 通过`merge`修改并且没有持久化
+    Runtime,
+    ConfigFile,
+    /// `LOG_LEVEL`和`LOG_FILE_LEVEL`
+    LegacyEnv,
+    Env,
+    CommandLine,
+}
+
+impl ConfigLayer {
+    const ALL: [ConfigLayer; 7] = [
+        ConfigLayer::Default,
+        ConfigLayer::AutoConfig,
+        ConfigLayer::Runtime,
+        ConfigLayer::ConfigFile,
+        ConfigLayer::LegacyEnv,
+        ConfigLayer::Env,
+        ConfigLayer::CommandLine,
+    ];
+
+    /// 会出现在figment的错误信息里，同时用来在metadata里区分来源
+    fn name(self) -> &'static str {
+        match self {
+            ConfigLayer::Default => "默认配置",
+            ConfigLayer::AutoConfig => "webui修改的配置",
+            ConfigLayer::Runtime => "运行时修改的配置",
+            ConfigLayer::ConfigFile => "配置文件",
+            ConfigLayer::LegacyEnv => "日志等级环境变量",
+            ConfigLayer::Env => "`CP_`环境变量",
+            ConfigLayer::CommandLine => "命令行参数",
+        }
+    }
+
+    pub(super) fn wrap<P: Provider>(self, provider: P) -> Tagged<P> {
+        Tagged {
+            layer: self,
+            provider,
+        }
+    }
+}
+
+/// 把figment的metadata名称替换为`ConfigLayer`的名称
+pub(super) struct Tagged<P> {
+    layer: ConfigLayer,
+    provider: P,
+}
+
+impl<P: Provider> Provider for Tagged<P> {
+    fn metadata(&self) -> Metadata {
+        let mut metadata = self.provider.metadata();
+        metadata.name = self.layer.name().into();
+        metadata
+    }
+
+    fn data(&self) -> Result<Map<Profile, Dict>, figment::Error> {
+        self.provider.data()
+    }
+
+    fn profile(&self) -> Option<Profile> {
+        self.provider.profile()
+    }
+}
+
+/// 某个配置项的值来自哪里
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ConfigSource {
+    Default,
+    AutoConfig(PathBuf),
+    ConfigFile(PathBuf),
+    /// 环境变量名
+    Env(String),
+    CommandLine,
+    /// 运行时通过`merge`修改并且没有持久化
+    Runtime,
+}
+
+impl Display for ConfigSource {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConfigSource::Default => write!(f, "默认值"),
+            ConfigSource::AutoConfig(path) => write!(f, "webui修改的配置 {}", path.display()),
+            ConfigSource::ConfigFile(path) => write!(f, "配置文件 {}", path.display()),
+            ConfigSource::Env(name) => write!(f, "环境变量 {}", name),
+            ConfigSource::CommandLine => write!(f, "命令行参数"),
+            ConfigSource::Runtime => write!(f, "运行时修改(未持久化)"),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Provenance {
+    /// 以`.`分隔的完整路径
+    pub key: String,
+    pub value: serde_json::Value,
+    pub source: ConfigSource,
+}
+
+/// 列出当前生效的每个配置项的值和来源
+pub fn provenance() -> anyhow::Result<Vec<Provenance>> {
+    let figment = figment()?;
+    let mut loaded = BTreeMap::new();
+    flatten(
+        String::new(),
+        serde_json::to_value(figment.extract::<Config>()?)?,
+        &mut loaded,
+    );
+    let mut effective = BTreeMap::new();
+    flatten(
+        String::new(),
+        serde_json::to_value(&**get_config())?,
+        &mut effective,
+    );
+
+    Ok(effective
+        .into_iter()
+        .map(|(key, value)| {
+            let source = if loaded.get(&key) != Some(&value) {
+                ConfigSource::Runtime
+            } else {
+                find_source(&figment, &key)
+            };
+            Provenance { key, value, source }
+        })
+        .collect())
+}
+
+/// 没有记录来源的值只可能是由serde的默认值填充的
+pub(super) fn find_source(figment: &Figment, key: &str) -> ConfigSource {
+    match figment.find_metadata(key) {
+        Some(metadata) => source_of(metadata, key),
+        None => ConfigSource::Default,
+    }
+}
+
+/// 根据`ConfigLayer::wrap`设置的名称判断来源
+pub(super) fn source_of(metadata: &Metadata, key: &str) -> ConfigSource {
+    let path = || {
+        metadata
+            .source
+            .as_ref()
+            .and_then(|source| source.file_path())
+            .map(ToOwned::to_owned)
+            .unwrap_or_default()
+    };
+    match ConfigLayer::ALL
+        .into_iter()
+        .find(|layer| layer.name() == metadata.name)
+    {
+        Some(ConfigLayer::AutoConfig) => ConfigSource::AutoConfig(path()),
+        Some(ConfigLayer::Runtime) => ConfigSource::Runtime,
+        Some(ConfigLayer::ConfigFile) => ConfigSource::ConfigFile(path()),
+        Some(ConfigLayer::LegacyEnv) => ConfigSource::Env(match key {
+            "log.level" => "LOG_LEVEL".into(),
+            _ => "LOG_FILE_LEVEL".into(),
+        }),
+        Some(ConfigLayer::Env) => {
+            ConfigSource::Env(format!("CP_{}", key.replace('.', "__").to_uppercase()))
+        }
+        Some(ConfigLayer::CommandLine) => ConfigSource::CommandLine,
+        Some(ConfigLayer::Default) | None => ConfigSource::Default,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use figment::providers::{Format, Serialized, Toml};
+    use figment::Figment;
+
+    use crate::configure::provenance::{find_source, ConfigLayer, ConfigSource};
+
+    #[test]
+    fn test_find_source() {
+        let figment = Figment::new()
+            .merge(ConfigLayer::Default.wrap(Toml::string("[http]\nbind = \"127.0.0.1:1\"")))
+            .merge(ConfigLayer::Env.wrap(Serialized::default("http.session_ttl", "3d")))
+            .merge(ConfigLayer::LegacyEnv.wrap(Serialized::default("log.level", "warn")))
+            .merge(ConfigLayer::CommandLine.wrap(Serialized::default("storage.data_dir", "/")));
+
+        assert_eq!(find_source(&figment, "http.bind"), ConfigSource::Default);
+        assert_eq!(
+            find_source(&figment, "http.session_ttl"),
+            ConfigSource::Env("CP_HTTP__SESSION_TTL".into())
+        );
+        assert_eq!(
+            find_source(&figment, "log.level"),
+            ConfigSource::Env("LOG_LEVEL".into())
+        );
+        assert_eq!(
+            find_source(&figment, "storage.data_dir"),
+            ConfigSource::CommandLine
+        );
+    }
+}
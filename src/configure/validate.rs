@@ -0,0 +1,466 @@
+use std::collections::BTreeMap;
+use std::fmt::{Display, Formatter};
+use std::io::ErrorKind;
+use std::net::{SocketAddr, UdpSocket};
+use std::path::Path;
+use std::time::Duration;
+
+use figment::{Figment, Provider};
+
+use crate::configure::provenance::{find_source, source_of};
+use crate::configure::{flatten, Config, ConfigSource, ListenAddr};
+
+const MIN_REFRESH_LIMIT: Duration = Duration::from_millis(100);
+const MAX_REFRESH_LIMIT: Duration = Duration::from_secs(60 * 60);
+/// 日志文件太小时会频繁切换文件
+const MIN_LOG_FILE_SIZE: u64 = 1024;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Severity {
+    /// 只记录日志，不影响配置生效
+    Warning,
+    /// 配置会被拒绝
+    Error,
+}
+
+/// 配置中的一个问题
+#[derive(Clone, Debug)]
+pub struct Issue {
+    pub severity: Severity,
+    /// 以`.`分隔的完整路径
+    pub key: String,
+    pub message: String,
+    /// 出问题的值来自哪里
+    pub source: Option<ConfigSource>,
+    /// 在配置文件中的行号，从1开始
+    pub line: Option<usize>,
+}
+
+impl Issue {
+    fn error(key: &str, message: impl Into<String>) -> Self {
+        Issue::new(Severity::Error, key, message)
+    }
+
+    fn warning(key: &str, message: impl Into<String>) -> Self {
+        Issue::new(Severity::Warning, key, message)
+    }
+
+    fn new(severity: Severity, key: &str, message: impl Into<String>) -> Self {
+        Issue {
+            severity,
+            key: key.to_owned(),
+            message: message.into(),
+            source: None,
+            line: None,
+        }
+    }
+
+    pub fn is_error(&self) -> bool {
+        self.severity == Severity::Error
+    }
+
+    /// 补充来源以及在配置文件中的行号
+    fn locate(mut self, source: ConfigSource) -> Self {
+        if let ConfigSource::ConfigFile(path) = &source {
+            self.line = find_line(path, &self.key);
+        }
+        self.source = Some(source);
+        self
+    }
+}
+
+impl Display for Issue {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self.severity {
+            Severity::Warning => write!(f, "[警告] ")?,
+            Severity::Error => write!(f, "[错误] ")?,
+        }
+        write!(f, "{}: {}", self.key, self.message)?;
+        match (&self.source, self.line) {
+            (Some(source), Some(line)) => write!(f, " ({}:{})", source, line),
+            (Some(source), None) => write!(f, " ({})", source),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// 包含至少一个错误的全部问题
+#[derive(Debug)]
+pub struct ConfigError(pub Vec<Issue>);
+
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "配置无效:")?;
+        for issue in &self.0 {
+            write!(f, "\n  {}", issue)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl From<figment::Error> for ConfigError {
+    fn from(err: figment::Error) -> Self {
+        ConfigError(
+            err.into_iter()
+                .map(|err| {
+                    let key = err.path.join(".");
+                    let issue = Issue::error(&key, err.kind.to_string());
+                    match &err.metadata {
+                        Some(metadata) => issue.locate(source_of(metadata, &key)),
+                        None => issue,
+                    }
+                })
+                .collect(),
+        )
+    }
+}
+
+/// 有错误时返回`ConfigError`，否则返回全部警告
+pub(super) fn into_result(issues: Vec<Issue>) -> Result<Vec<Issue>, ConfigError> {
+    if issues.iter().any(Issue::is_error) {
+        Err(ConfigError(issues))
+    } else {
+        Ok(issues)
+    }
+}
+
+/// 检查从各个来源读取的配置
+/// 环境变量和其他程序共用`CP_`前缀，所以不检查环境变量中的未知配置项
+pub(super) fn validate_loaded(config: &Config, figment: &Figment) -> anyhow::Result<Vec<Issue>> {
+    let mut issues = check(config)
+        .into_iter()
+        .map(|issue| {
+            let source = find_source(figment, &issue.key);
+            issue.locate(source)
+        })
+        .collect::<Vec<_>>();
+
+    let known = known_keys(config)?;
+    for profile in figment.data()?.values() {
+        let mut values = BTreeMap::new();
+        flatten(String::new(), serde_json::to_value(profile)?, &mut values);
+        for key in values.into_keys().filter(|key| !known.contains_key(key)) {
+            match find_source(figment, &key) {
+                ConfigSource::Env(_) => continue,
+                source => issues.push(Issue::warning(&key, "未知的配置项").locate(source)),
+            }
+        }
+    }
+    Ok(issues)
+}
+
+/// 检查运行时修改的配置，`patch`中的未知配置项视为错误
+pub(super) fn validate_patch(
+    config: &Config,
+    patch: &serde_json::Value,
+) -> anyhow::Result<Vec<Issue>> {
+    let mut issues = check(config);
+    let known = known_keys(config)?;
+    let mut values = BTreeMap::new();
+    flatten(String::new(), patch.clone(), &mut values);
+    issues.extend(
+        values
+            .into_keys()
+            .filter(|key| !known.contains_key(key))
+            .map(|key| Issue::error(&key, "未知的配置项")),
+    );
+    Ok(issues
+        .into_iter()
+        .map(|issue| issue.locate(ConfigSource::Runtime))
+        .collect())
+}
+
+/// `patch`中被配置文件、环境变量或命令行参数覆盖的配置项，修改不会生效
+pub(super) fn overridden(figment: &Figment, patch: &serde_json::Value) -> Vec<Issue> {
+    let mut values = BTreeMap::new();
+    flatten(String::new(), patch.clone(), &mut values);
+    values
+        .into_keys()
+        .filter_map(|key| match find_source(figment, &key) {
+            source @ (ConfigSource::ConfigFile(_)
+            | ConfigSource::Env(_)
+            | ConfigSource::CommandLine) => {
+                Some(Issue::warning(&key, "被更高优先级的配置覆盖，修改不会生效").locate(source))
+            }
+            _ => None,
+        })
+        .collect()
+}
+
+fn known_keys(config: &Config) -> anyhow::Result<BTreeMap<String, serde_json::Value>> {
+    let mut known = BTreeMap::new();
+    flatten(String::new(), serde_json::to_value(config)?, &mut known);
+    Ok(known)
+}
+
+/// 语义上的检查，类型错误在反序列化的时候就已经被拒绝了
+fn check(config: &Config) -> Vec<Issue> {
+    let mut issues = Vec::new();
+    let http = &config.http;
+
+    if !(MIN_REFRESH_LIMIT..=MAX_REFRESH_LIMIT).contains(&http.system_info_refresh_limit) {
+        issues.push(Issue::error(
+            "http.system_info_refresh_limit",
+            format!("必须在{:?}到{:?}之间", MIN_REFRESH_LIMIT, MAX_REFRESH_LIMIT),
+        ));
+    }
+
+    issues.extend(check_listen_addrs(&http.bind));
+    issues.extend(check_tls(config));
+
+    if http.trusted_proxies.iter().any(|net| net.prefix_len() == 0) {
+        issues.push(Issue::warning(
+            "http.trusted_proxies",
+            "信任了全部地址，客户端可以通过Forwarded或X-Forwarded-For伪造ip",
+        ));
+    }
+
+    issues.extend(check_rate_limit(config));
+
+    if config.log.max_file_size.get_bytes() < MIN_LOG_FILE_SIZE {
+        issues.push(Issue::error(
+            "log.max_file_size",
+            format!("不能小于{}字节", MIN_LOG_FILE_SIZE),
+        ));
+    }
+    if config.log.queue_capacity == 0 {
+        issues.push(Issue::error("log.queue_capacity", "不能为0"));
+    }
+
+    for (key, value) in [
+        ("http.session_ttl", http.session_ttl),
+        ("http.session_idle_timeout", http.session_idle_timeout),
+        ("http.session_gc_interval", http.session_gc_interval),
+    ] {
+        if value.is_zero() {
+            issues.push(Issue::error(key, "不能为0"));
+        }
+    }
+    if http.session_idle_timeout > http.session_ttl {
+        issues.push(Issue::warning(
+            "http.session_idle_timeout",
+            "大于session_ttl，闲置超时实际上不会生效",
+        ));
+    }
+
+    issues
+}
+
+fn check_bind(key: &str, addr: SocketAddr) -> Option<Issue> {
+    if addr.port() == 0 {
+        return Some(Issue::error(key, "端口不能为0"));
+    }
+    // 绑定一个临时的udp端口来判断地址是否属于本机，不会和正在监听的端口冲突
+    if !addr.ip().is_unspecified() {
+        if let Err(err) = UdpSocket::bind(SocketAddr::new(addr.ip(), 0)) {
+            if err.kind() == ErrorKind::AddrNotAvailable {
+                return Some(Issue::error(
+                    key,
+                    format!("{}不是本机任何网卡上的地址", addr.ip()),
+                ));
+            }
+        }
+    }
+    if addr.port() < 1024 {
+        return Some(Issue::warning(
+            key,
+            "小于1024的端口通常需要root权限或者CAP_NET_BIND_SERVICE",
+        ));
+    }
+    None
+}
+
+fn check_listen_addrs(addrs: &[ListenAddr]) -> Vec<Issue> {
+    if addrs.is_empty() {
+        return vec![Issue::error("http.bind", "至少需要一个监听地址")];
+    }
+    let mut issues = Vec::new();
+    for (i, addr) in addrs.iter().enumerate() {
+        if addrs[..i].contains(addr) {
+            issues.push(Issue::error("http.bind", format!("{}重复", addr)));
+            continue;
+        }
+        match addr {
+            ListenAddr::Tcp(addr) => issues.extend(check_bind("http.bind", *addr)),
+            ListenAddr::Unix(_) if cfg!(not(unix)) => issues.push(Issue::error(
+                "http.bind",
+                "当前平台不支持Unix domain socket",
+            )),
+            ListenAddr::Unix(_) => {}
+        }
+    }
+    issues
+}
+
+fn check_rate_limit(config: &Config) -> Vec<Issue> {
+    let limit = &config.http.rate_limit;
+    let mut issues = Vec::new();
+    for (key, value) in [
+        (
+            "http.rate_limit.requests_per_second",
+            limit.requests_per_second,
+        ),
+        ("http.rate_limit.login_per_minute", limit.login_per_minute),
+    ] {
+        if !(value > 0.0 && value.is_finite()) {
+            issues.push(Issue::error(key, "必须大于0"));
+        }
+    }
+    for (key, value) in [
+        ("http.rate_limit.burst", limit.burst),
+        ("http.rate_limit.login_burst", limit.login_burst),
+        ("http.rate_limit.lockout_threshold", limit.lockout_threshold),
+    ] {
+        if value == 0 {
+            issues.push(Issue::error(key, "不能为0"));
+        }
+    }
+    if limit.lockout_duration.is_zero() {
+        issues.push(Issue::error("http.rate_limit.lockout_duration", "不能为0"));
+    }
+    if limit.lockout_max_duration < limit.lockout_duration {
+        issues.push(Issue::error(
+            "http.rate_limit.lockout_max_duration",
+            "不能小于lockout_duration",
+        ));
+    }
+    issues
+}
+
+fn check_tls(config: &Config) -> Vec<Issue> {
+    let http = &config.http;
+    let tls = &http.tls;
+    let mut issues = Vec::new();
+
+    if !tls.enabled {
+        if http.tcp_binds().any(|addr| !addr.ip().is_loopback()) {
+            issues.push(Issue::warning(
+                "http.tls.enabled",
+                "监听的不是本机地址但是没有启用TLS，密码会以明文传输",
+            ));
+        }
+        if tls.redirect_bind.is_some() {
+            issues.push(Issue::warning(
+                "http.tls.redirect_bind",
+                "没有启用TLS，不会生效",
+            ));
+        }
+        return issues;
+    }
+
+    if http.tcp_binds().next().is_none() {
+        issues.push(Issue::warning(
+            "http.tls.enabled",
+            "只对TCP监听地址生效，Unix domain socket始终使用HTTP",
+        ));
+    }
+
+    let cert = config.storage.resolve(&tls.cert);
+    let key = config.storage.resolve(&tls.key);
+    // 只有两个文件都不存在的时候才会生成自签名证书，避免覆盖掉用户的文件
+    if !(tls.self_signed && !cert.exists() && !key.exists()) {
+        for (name, path) in [("http.tls.cert", cert), ("http.tls.key", key)] {
+            if !path.exists() {
+                issues.push(Issue::error(name, format!("{}不存在", path.display())));
+            }
+        }
+    }
+
+    if let Some(redirect) = tls.redirect_bind {
+        if http.bind.contains(&ListenAddr::Tcp(redirect)) {
+            issues.push(Issue::error(
+                "http.tls.redirect_bind",
+                "不能和http.bind中的地址相同",
+            ));
+        } else {
+            issues.extend(check_bind("http.tls.redirect_bind", redirect));
+        }
+    }
+    issues
+}
+
+/// 在toml文件中查找某个key所在的行，只支持`[table]`和`a.b = x`两种写法
+fn find_line(path: &Path, key: &str) -> Option<usize> {
+    let text = std::fs::read_to_string(path).ok()?;
+    let mut table = String::new();
+    for (i, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if let Some(header) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+            table = header.trim().to_owned();
+            continue;
+        }
+        if let Some((name, _)) = line.split_once('=') {
+            let name = name
+                .split('.')
+                .map(|part| part.trim().trim_matches('"'))
+                .collect::<Vec<_>>()
+                .join(".");
+            let full = if table.is_empty() {
+                name
+            } else {
+                format!("{}.{}", table, name)
+            };
+            if full == key {
+                return Some(i + 1);
+            }
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use serde_json::json;
+
+    use crate::configure::validate::{check, find_line, validate_patch};
+    use crate::configure::{get_config, init_test_configure, Config};
+
+    #[test]
+    fn test_check() -> anyhow::Result<()> {
+        let _config = init_test_configure()?;
+        let mut config = Config::clone(&get_config());
+        config.http.bind = vec!["127.0.0.1:8686".parse()?, "unix:panel.sock".parse()?];
+        assert!(check(&config).is_empty());
+
+        config.http.system_info_refresh_limit = Duration::ZERO;
+        config.http.bind = vec!["127.0.0.1:0".parse()?];
+        config.http.session_ttl = Duration::from_secs(1);
+        let issues = check(&config);
+        let keys = issues
+            .iter()
+            .map(|issue| (issue.key.as_str(), issue.is_error()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            keys,
+            [
+                ("http.system_info_refresh_limit", true),
+                ("http.bind", true),
+                ("http.session_idle_timeout", false),
+            ]
+        );
+
+        let issues = validate_patch(&get_config(), &json!({ "http": { "bindd": 1 } }))?;
+        assert_eq!(issues.len(), 1);
+        assert_eq!(issues[0].key, "http.bindd");
+        Ok(())
+    }
+
+    #[test]
+    fn test_find_line() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("config.toml");
+        std::fs::write(
+            &path,
+            "[http]\nbind = \"0.0.0.0:1\"\n\n[log]\nlevel = \"info\"\nhttp.\"session_ttl\" = \"1d\"\n",
+        )?;
+        assert_eq!(find_line(&path, "http.bind"), Some(2));
+        assert_eq!(find_line(&path, "log.level"), Some(5));
+        assert_eq!(find_line(&path, "log.http.session_ttl"), Some(6));
+        assert_eq!(find_line(&path, "http.session_ttl"), None);
+        Ok(())
+    }
+}
@@ -0,0 +1,12 @@
+use sea_orm_migration::{MigrationTrait, MigratorTrait};
+
+mod m20230115_000001_create_users_table;
+
+pub struct Migrator;
+
+#[async_trait::async_trait]
+impl MigratorTrait for Migrator {
+    fn migrations() -> Vec<Box<dyn MigrationTrait>> {
+        vec![Box::new(m20230115_000001_create_users_table::Migration)]
+    }
+}
@@ -0,0 +1,78 @@
+use once_cell::sync::OnceCell;
+use sea_orm::{DatabaseConnection, SqlxSqliteConnector};
+use sea_orm_migration::MigratorTrait;
+use sqlx::sqlite::{SqliteConnectOptions, SqlitePoolOptions};
+use sqlx::ConnectOptions;
+
+use crate::configure::get_config;
+use crate::database::migration::Migrator;
+
+pub mod entity;
+mod migration;
+
+static DATABASE: OnceCell<DatabaseConnection> = OnceCell::new();
+
+/// 连接数据库并执行全部未执行的迁移
+pub async fn init_database() -> anyhow::Result<()> {
+    let db = connect(database_options()).await?;
+    // 与`init_configure`一样，跑tests的时候可能会重复初始化
+    #[cfg(test)]
+    DATABASE.set(db).ok();
+    #[cfg(not(test))]
+    DATABASE.set(db).expect("DATABASE意外的重复初始化");
+    Ok(())
+}
+
+/// 在test中使用内存数据库代替配置中的数据库
+#[cfg(test)]
+pub async fn init_test_database() -> anyhow::Result<&'static DatabaseConnection> {
+    if DATABASE.get().is_none() {
+        let db = connect("sqlite::memory:".parse()?).await?;
+        DATABASE.set(db).ok();
+    }
+    Ok(get_database())
+}
+
+/// 直接使用路径而不是拼接url，路径中有`?`、`#`或者不是UTF-8时也能正确打开
+fn database_options() -> SqliteConnectOptions {
+    SqliteConnectOptions::new()
+        .filename(get_config().storage.database())
+        .create_if_missing(true)
+}
+
+async fn open(mut options: SqliteConnectOptions) -> anyhow::Result<DatabaseConnection> {
+    // sqlx的语句日志太多了，只在trace级别输出
+    options.log_statements(tracing::log::LevelFilter::Trace);
+    // 与sea-orm连接sqlite时的默认设置一样只使用一个连接
+    let pool = SqlitePoolOptions::new()
+        .max_connections(1)
+        .connect_with(options)
+        .await?;
+    Ok(SqlxSqliteConnector::from_sqlx_sqlite_pool(pool))
+}
+
+pub async fn connect(options: SqliteConnectOptions) -> anyhow::Result<DatabaseConnection> {
+    let db = open(options).await?;
+    Migrator::up(&db, None).await?;
+    Ok(db)
+}
+
+/// 对配置中的数据库执行全部未执行的迁移，返回本次执行的迁移名
+pub async fn migrate() -> anyhow::Result<Vec<String>> {
+    let db = open(database_options()).await?;
+    let pending = Migrator::get_pending_migrations(&db).await?.len();
+    Migrator::up(&db, None).await?;
+    // 迁移总是按顺序执行，所以未执行的一定是最后的几个
+    let migrations = Migrator::migrations();
+    Ok(migrations[migrations.len() - pending..]
+        .iter()
+        .map(|migration| migration.name().to_owned())
+        .collect())
+}
+
+#[inline]
+pub fn get_database() -> &'static DatabaseConnection {
+    DATABASE
+        .get()
+        .expect("在程序一开始就应该已经调用`init_database`")
+}
@@ -1,8 +1,13 @@
-use crate::configure::init_configure;
+use crate::configure::get_config;
+use crate::database::{get_database, init_database};
+use crate::user::init_admin;
 
 /// 初始化运行环境
 /// 负责创建各种目录, 文件等等可能不存在的资源
-pub fn init_environment() -> anyhow::Result<()> {
-    init_configure()?;
+/// 需要先调用`init_configure`
+pub async fn init_environment() -> anyhow::Result<()> {
+    get_config().storage.create_dirs()?;
+    init_database().await?;
+    init_admin(get_database()).await?;
     Ok(())
-}
\ No newline at end of file
+}
@@ -0,0 +1,204 @@
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use axum::extract::FromRequestParts;
+use axum::http::request::Parts;
+use axum::http::Request;
+use axum::middleware::Next;
+use axum::response::Response;
+use axum_sessions::async_session::{Session, SessionStore};
+use axum_sessions::SessionHandle;
+use chrono::Utc;
+use parking_lot::Mutex;
+use sea_orm::{ConnectionTrait, DatabaseConnection, DbErr, EntityTrait};
+use tracing::error;
+
+use crate::database::entity::user;
+use crate::database::get_database;
+use crate::http::client_info::ClientInfo;
+use crate::http::error::{AnyResult, Error, ErrorKind};
+use crate::http::rate_limit::RateLimiter;
+use crate::http::rocksdb_session_store::RocksdbStore;
+use crate::http::trace;
+
+/// 登录成功后在session里保存用户id使用的key
+pub const SESSION_USER_ID: &str = "user_id";
+/// 登录时用户的凭据版本，修改密码后旧的session不再有效
+pub const SESSION_CREDENTIAL_VERSION: &str = "credential_version";
+/// 登录时间(unix时间戳)，用于限制session从登录开始的最长有效期
+pub const SESSION_LOGIN_AT: &str = "login_at";
+/// 登录时的客户端ip和user agent，用于在session列表中展示
+pub const SESSION_CLIENT_IP: &str = "client_ip";
+pub const SESSION_USER_AGENT: &str = "user_agent";
+
+/// `SessionWatch`重新查询用户凭据的最短间隔
+const CREDENTIAL_RECHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// 当前登录的用户
+/// 可以直接作为axum的extractor使用，未登录时返回401
+/// 也会作为graphql的data传入，由`LoginGuard`检查
+#[derive(Debug, Clone, Copy)]
+pub struct CurrentUser {
+    pub id: i32,
+}
+
+#[async_trait]
+impl<S> FromRequestParts<S> for CurrentUser
+where
+    S: Send + Sync,
+{
+    type Rejection = Error;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let session = parts
+            .extensions
+            .get::<SessionHandle>()
+            .ok_or_else(|| Error::from(anyhow::anyhow!("没有安装SessionLayer")))?;
+        let (id, version) = {
+            let session = session.read().await;
+            (
+                session.get::<i32>(SESSION_USER_ID),
+                session.get::<String>(SESSION_CREDENTIAL_VERSION),
+            )
+        };
+        let id = id.ok_or_else(|| Error::new(ErrorKind::Unauthorized, "需要登录"))?;
+
+        if credential_valid(get_database(), id, version.as_deref())
+            .await
+            .map_err(Error::from)?
+        {
+            trace::record_user(id);
+            Ok(CurrentUser { id })
+        } else {
+            session.write().await.destroy();
+            Err(Error::new(
+                ErrorKind::Unauthorized,
+                "登录已失效，请重新登录",
+            ))
+        }
+    }
+}
+
+/// 用户被删除或者修改了密码之后，之前登录的session不再有效
+async fn credential_valid(
+    db: &impl ConnectionTrait,
+    id: i32,
+    version: Option<&str>,
+) -> Result<bool, DbErr> {
+    Ok(user::Entity::find_by_id(id)
+        .one(db)
+        .await?
+        .is_some_and(|user| version == Some(&*crate::user::credential_version(&user))))
+}
+
+/// 建立websocket连接时的session，订阅在推送时用它重新检查登录是否仍然有效
+/// 连接建立之后不会再经过`CurrentUser`的检查，注销、撤销session或者清空全部session之后订阅也需要停止
+#[derive(Clone)]
+pub struct SessionWatch(Arc<SessionWatchInner>);
+
+struct SessionWatchInner {
+    store: RocksdbStore,
+    db: DatabaseConnection,
+    session_id: String,
+    user_id: i32,
+    /// 上一次检查用户凭据的时间
+    credential_checked: Mutex<Option<Instant>>,
+}
+
+impl SessionWatch {
+    pub fn new(
+        store: RocksdbStore,
+        db: DatabaseConnection,
+        session_id: String,
+        user_id: i32,
+    ) -> Self {
+        SessionWatch(Arc::new(SessionWatchInner {
+            store,
+            db,
+            session_id,
+            user_id,
+            credential_checked: Mutex::new(None),
+        }))
+    }
+
+    /// session已经不存在或者用户凭据发生变化时返回false
+    /// session每次都检查，需要查询数据库的用户凭据最多每`CREDENTIAL_RECHECK_INTERVAL`检查一次
+    pub async fn check(&self) -> bool {
+        match self.validate().await {
+            Ok(valid) => valid,
+            Err(err) => {
+                error!("检查websocket连接的session失败: {:#}", err);
+                false
+            }
+        }
+    }
+
+    async fn validate(&self) -> anyhow::Result<bool> {
+        let inner = &self.0;
+        let session = match inner.store.active_session(&inner.session_id)? {
+            Some(session) => session,
+            None => return Ok(false),
+        };
+        if session.get::<i32>(SESSION_USER_ID) != Some(inner.user_id) {
+            return Ok(false);
+        }
+        let checked = *inner.credential_checked.lock();
+        if checked.is_some_and(|checked| checked.elapsed() < CREDENTIAL_RECHECK_INTERVAL) {
+            return Ok(true);
+        }
+        let version = session.get::<String>(SESSION_CREDENTIAL_VERSION);
+        let valid = credential_valid(&inner.db, inner.user_id, version.as_deref()).await?;
+        if valid {
+            *inner.credential_checked.lock() = Some(Instant::now());
+        }
+        Ok(valid)
+    }
+}
+
+/// 拒绝所有未登录的请求，并把`CurrentUser`放入request extensions供后面的handler使用
+pub async fn require_login<B>(user: CurrentUser, mut req: Request<B>, next: Next<B>) -> Response {
+    req.extensions_mut().insert(user);
+    next.run(req).await
+}
+
+/// 校验用户名和密码，成功后把用户绑定到session上
+/// 用户名或密码错误时返回`None`，尝试过于频繁或者被锁定时返回429
+pub async fn login(
+    limiter: &RateLimiter,
+    store: &RocksdbStore,
+    session: &mut Session,
+    client: &ClientInfo,
+    username: &str,
+    password: String,
+) -> AnyResult<Option<user::Model>> {
+    limiter.check_login(client.ip, username)?;
+    let user = match crate::user::authenticate(get_database(), username, password).await? {
+        Some(user) => user,
+        None => {
+            limiter.login_failed(client.ip, username).await?;
+            return Ok(None);
+        }
+    };
+    limiter.login_succeeded(client.ip, username).await?;
+    // 登录后更换session id，避免session fixation
+    // `regenerate`不会删除旧的session，需要主动从数据库里删除
+    store.destroy_session(session.clone()).await?;
+    session.regenerate();
+    session.insert(SESSION_USER_ID, user.id)?;
+    session.insert(
+        SESSION_CREDENTIAL_VERSION,
+        crate::user::credential_version(&user),
+    )?;
+    trace::record_user(user.id);
+    session.insert(SESSION_LOGIN_AT, Utc::now().timestamp())?;
+    session.insert(SESSION_CLIENT_IP, client.ip.to_string())?;
+    if let Some(user_agent) = &client.user_agent {
+        session.insert(SESSION_USER_AGENT, user_agent)?;
+    }
+    Ok(Some(user))
+}
+
+pub fn logout(session: &mut Session) {
+    session.destroy();
+}
@@ -0,0 +1,176 @@
+use std::net::{IpAddr, SocketAddr};
+
+use async_trait::async_trait;
+use axum::extract::{ConnectInfo, FromRequestParts};
+use axum::http::header::USER_AGENT;
+use axum::http::request::Parts;
+use axum::http::{HeaderMap, Request};
+use axum::middleware::Next;
+use axum::response::Response;
+use forwarded_header_value::ForwardedHeaderValue;
+use ipnet::IpNet;
+
+use crate::configure::get_config;
+use crate::http::error::Error;
+
+/// 发起请求的客户端信息
+#[derive(Debug, Clone)]
+pub struct ClientInfo {
+    pub ip: IpAddr,
+    pub user_agent: Option<String>,
+}
+
+/// 考虑了反向代理之后的客户端ip，由`resolve_client_ip`放入request extensions
+#[derive(Debug, Clone, Copy)]
+pub struct ClientIp(pub IpAddr);
+
+/// 从`Forwarded`或`X-Forwarded-For`中取出经过的全部地址，最前面的是最初的客户端
+/// 同一个header出现多次时按顺序拼接
+fn forwarded_chain(headers: &HeaderMap) -> Option<Vec<Option<IpAddr>>> {
+    let join = |name: &str| {
+        let values = headers
+            .get_all(name)
+            .iter()
+            .map(|value| value.to_str().ok())
+            .collect::<Option<Vec<_>>>()?;
+        (!values.is_empty()).then(|| values.join(","))
+    };
+    let value = match join("forwarded") {
+        Some(value) => ForwardedHeaderValue::from_forwarded(&value).ok()?,
+        None => ForwardedHeaderValue::from_x_forwarded_for(&join("x-forwarded-for")?).ok()?,
+    };
+    Some(
+        value
+            .iter()
+            .map(|stanza| stanza.forwarded_for_ip())
+            .collect(),
+    )
+}
+
+/// 只有直接连接的对端是受信任的代理时才使用转发的地址
+/// 从离自己最近的一跳开始往前找，第一个不受信任的地址就是真实的客户端，更前面的地址可能是客户端伪造的
+pub fn client_ip(peer: IpAddr, headers: &HeaderMap, trusted: &[IpNet]) -> IpAddr {
+    let is_trusted = |ip: &IpAddr| trusted.iter().any(|net| net.contains(ip));
+    // 监听`[::]`时ipv4的客户端会以`::ffff:a.b.c.d`的形式出现
+    let mut client = peer.to_canonical();
+    if !is_trusted(&client) {
+        return client;
+    }
+    for ip in forwarded_chain(headers)
+        .unwrap_or_default()
+        .into_iter()
+        .rev()
+    {
+        match ip {
+            Some(ip) => client = ip.to_canonical(),
+            // 代理隐藏了客户端地址，无法继续往前找
+            None => break,
+        }
+        if !is_trusted(&client) {
+            break;
+        }
+    }
+    client
+}
+
+/// 解析客户端ip放入request extensions
+pub async fn resolve_client_ip<B>(mut req: Request<B>, next: Next<B>) -> Response {
+    if let Some(ConnectInfo(peer)) = req.extensions().get::<ConnectInfo<SocketAddr>>() {
+        let ip = client_ip(peer.ip(), req.headers(), &get_config().http.trusted_proxies);
+        req.extensions_mut().insert(ClientIp(ip));
+    }
+    next.run(req).await
+}
+
+#[async_trait]
+impl<S> FromRequestParts<S> for ClientInfo
+where
+    S: Send + Sync,
+{
+    type Rejection = Error;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let ClientIp(ip) = *parts.extensions.get::<ClientIp>().ok_or_else(|| {
+            Error::from(anyhow::anyhow!(
+                "缺少ClientIp，没有添加`resolve_client_ip`中间件"
+            ))
+        })?;
+        Ok(ClientInfo {
+            ip,
+            user_agent: parts
+                .headers
+                .get(USER_AGENT)
+                .and_then(|v| v.to_str().ok())
+                .map(ToOwned::to_owned),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::IpAddr;
+
+    use axum::http::{HeaderMap, HeaderValue};
+    use ipnet::IpNet;
+
+    use crate::http::client_info::client_ip;
+
+    #[test]
+    fn test_client_ip() {
+        let trusted: Vec<IpNet> = vec![
+            "127.0.0.0/8".parse().unwrap(),
+            "10.0.0.0/8".parse().unwrap(),
+        ];
+        let ip = |s: &str| s.parse::<IpAddr>().unwrap();
+        let headers = |pairs: &[(&'static str, &'static str)]| {
+            let mut headers = HeaderMap::new();
+            for (name, value) in pairs {
+                headers.append(*name, HeaderValue::from_static(value));
+            }
+            headers
+        };
+
+        let forwarded = headers(&[("x-forwarded-for", "6.6.6.6, 1.2.3.4, 10.0.0.2")]);
+        // 不受信任的对端发来的header会被忽略
+        assert_eq!(
+            client_ip(ip("8.8.8.8"), &forwarded, &trusted),
+            ip("8.8.8.8")
+        );
+        // 跳过受信任的代理，但不会相信客户端自己填写的地址
+        assert_eq!(
+            client_ip(ip("127.0.0.1"), &forwarded, &trusted),
+            ip("1.2.3.4")
+        );
+        assert_eq!(
+            client_ip(ip("::ffff:127.0.0.1"), &forwarded, &trusted),
+            ip("1.2.3.4")
+        );
+        assert_eq!(
+            client_ip(ip("127.0.0.1"), &headers(&[]), &trusted),
+            ip("127.0.0.1")
+        );
+
+        let forwarded = headers(&[
+            ("forwarded", "for=1.2.3.4"),
+            ("forwarded", "for=\"[::1]\""),
+            ("x-forwarded-for", "6.6.6.6"),
+        ]);
+        assert_eq!(client_ip(ip("10.1.1.1"), &forwarded, &trusted), ip("::1"));
+        assert_eq!(
+            client_ip(
+                ip("10.1.1.1"),
+                &forwarded,
+                &["::1/128".parse().unwrap(), "10.0.0.0/8".parse().unwrap()]
+            ),
+            ip("1.2.3.4")
+        );
+        assert_eq!(
+            client_ip(
+                ip("10.1.1.1"),
+                &headers(&[("forwarded", "for=unknown")]),
+                &trusted
+            ),
+            ip("10.1.1.1")
+        );
+    }
+}
@@ -1,33 +1,210 @@
+use std::any::Any;
+use std::time::Duration;
+
+use async_graphql::Context;
+use axum::http::header::RETRY_AFTER;
 use axum::http::StatusCode;
 use axum::response::{IntoResponse, Response};
+use axum::Json;
+use sea_orm::DbErr;
+use serde::Serialize;
+use tracing::error;
+
+use crate::configure::ConfigError;
+use crate::http::request_id;
 
 pub type AnyResult<T> = Result<T, Error>;
 
+/// 错误的种类，`code`是稳定的，客户端可以依赖它判断错误类型
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind {
+    /// 请求的格式错误
+    BadRequest,
+    /// 请求的格式正确但是内容无效，例如配置校验失败
+    Validation,
+    /// 没有登录或者用户名密码错误
+    Unauthorized,
+    /// 已经登录但是没有权限
+    Forbidden,
+    NotFound,
+    /// 请求过于频繁或者登录失败次数过多
+    TooManyRequests,
+    /// 服务器内部错误，详细信息只记录在日志中
+    Internal,
+}
+
+impl ErrorKind {
+    pub fn code(self) -> &'static str {
+        match self {
+            ErrorKind::BadRequest => "BAD_REQUEST",
+            ErrorKind::Validation => "VALIDATION_FAILED",
+            ErrorKind::Unauthorized => "UNAUTHORIZED",
+            ErrorKind::Forbidden => "FORBIDDEN",
+            ErrorKind::NotFound => "NOT_FOUND",
+            ErrorKind::TooManyRequests => "TOO_MANY_REQUESTS",
+            ErrorKind::Internal => "INTERNAL",
+        }
+    }
+
+    pub fn status(self) -> StatusCode {
+        match self {
+            ErrorKind::BadRequest => StatusCode::BAD_REQUEST,
+            ErrorKind::Validation => StatusCode::UNPROCESSABLE_ENTITY,
+            ErrorKind::Unauthorized => StatusCode::UNAUTHORIZED,
+            ErrorKind::Forbidden => StatusCode::FORBIDDEN,
+            ErrorKind::NotFound => StatusCode::NOT_FOUND,
+            ErrorKind::TooManyRequests => StatusCode::TOO_MANY_REQUESTS,
+            ErrorKind::Internal => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+/// 返回给客户端的错误
+/// http接口返回`{code, message, request_id}`，graphql中`code`和`request_id`放在extensions里
+#[derive(Debug)]
 pub struct Error {
-    code: StatusCode,
-    msg: String,
+    kind: ErrorKind,
+    message: String,
+    /// 多久之后可以重试，会放在`Retry-After`中
+    retry_after: Option<Duration>,
 }
 
 impl Error {
-    pub fn status(code: StatusCode, msg: String) -> Self {
-        Error { code, msg }
+    pub fn new(kind: ErrorKind, message: impl Into<String>) -> Self {
+        Error {
+            kind,
+            message: message.into(),
+            retry_after: None,
+        }
+    }
+
+    /// 请求过于频繁，`retry_after`之后可以重试
+    pub fn too_many_requests(message: impl Into<String>, retry_after: Duration) -> Self {
+        Error {
+            retry_after: Some(retry_after),
+            ..Error::new(ErrorKind::TooManyRequests, message)
+        }
+    }
+
+    pub fn kind(&self) -> ErrorKind {
+        self.kind
     }
 }
 
+/// 识别出可以告诉客户端的错误，其他的都视为内部错误
 impl<E> From<E> for Error
 where
     E: Into<anyhow::Error>,
 {
     fn from(value: E) -> Self {
-        Error {
-            code: StatusCode::INTERNAL_SERVER_ERROR,
-            msg: value.into().to_string(),
+        let err = value.into();
+        if let Some(err) = err.downcast_ref::<ConfigError>() {
+            return Error::new(ErrorKind::Validation, err.to_string());
+        }
+        if let Some(DbErr::RecordNotFound(msg)) = err.downcast_ref::<DbErr>() {
+            return Error::new(ErrorKind::NotFound, msg);
         }
+        // 内部错误可能包含路径、sql之类的信息，只记录到日志，请求id在请求的span中
+        error!("处理请求时发生错误: {:#}", err);
+        Error::new(ErrorKind::Internal, "服务器内部错误")
     }
 }
 
+#[derive(Serialize)]
+struct ErrorBody<'a> {
+    code: &'static str,
+    message: &'a str,
+    request_id: Option<String>,
+}
+
 impl IntoResponse for Error {
     fn into_response(self) -> Response {
-        (self.code, self.msg).into_response()
+        let body = ErrorBody {
+            code: self.kind.code(),
+            message: &self.message,
+            request_id: request_id::current(),
+        };
+        let mut res = (self.kind.status(), Json(body)).into_response();
+        if let Some(retry_after) = self.retry_after {
+            res.headers_mut()
+                .insert(RETRY_AFTER, retry_after_secs(retry_after).into());
+        }
+        res
+    }
+}
+
+impl From<Error> for async_graphql::Error {
+    fn from(err: Error) -> Self {
+        use async_graphql::ErrorExtensions;
+
+        let code = err.kind.code();
+        let retry_after = err.retry_after;
+        async_graphql::Error::new(err.message).extend_with(|_, ext| {
+            ext.set("code", code);
+            if let Some(id) = request_id::current() {
+                ext.set("request_id", id);
+            }
+            if let Some(retry_after) = retry_after {
+                ext.set("retry_after", retry_after_secs(retry_after));
+            }
+        })
+    }
+}
+
+/// 读取graphql context中的数据，缺少时作为内部错误返回，这样错误里同样带有`code`和`request_id`
+pub fn ctx_data<'a, D: Any + Send + Sync>(ctx: &Context<'a>) -> AnyResult<&'a D> {
+    ctx.data::<D>()
+        .map_err(|err| Error::from(anyhow::anyhow!(err.message)))
+}
+
+/// `Retry-After`只支持整数秒，向上取整
+fn retry_after_secs(duration: Duration) -> u64 {
+    duration.as_secs() + u64::from(duration.subsec_nanos() > 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use async_graphql::{Context, EmptyMutation, EmptySubscription, Object, Request, Schema};
+    use serde_json::json;
+
+    use crate::configure::{init_test_configure, merge};
+    use crate::http::error::{ctx_data, Error, ErrorKind};
+
+    #[test]
+    fn test_error_kind() -> anyhow::Result<()> {
+        let _config = init_test_configure()?;
+        let err = Error::from(merge(json!({ "http": { "bindd": 1 } }), false).unwrap_err());
+        assert_eq!(err.kind(), ErrorKind::Validation);
+        assert!(err.message.contains("http.bindd"));
+
+        // 内部错误的信息不会返回给客户端
+        let err = Error::from(anyhow::anyhow!("/secret/path: permission denied"));
+        assert_eq!(err.kind(), ErrorKind::Internal);
+        assert!(!err.message.contains("secret"));
+
+        let err = async_graphql::Error::from(Error::new(ErrorKind::NotFound, "找不到"));
+        let code = err.extensions.unwrap().get("code").cloned();
+        assert_eq!(code, Some(async_graphql::Value::from("NOT_FOUND")));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_ctx_data() {
+        struct Query;
+
+        #[Object]
+        impl Query {
+            async fn value(&self, ctx: &Context<'_>) -> async_graphql::Result<u32> {
+                Ok(*ctx_data::<u32>(ctx)?)
+            }
+        }
+
+        let schema = Schema::new(Query, EmptyMutation, EmptySubscription);
+        let resp = schema.execute("{ value }").await;
+        let code = resp.errors[0].extensions.as_ref().unwrap().get("code");
+        assert_eq!(code, Some(&async_graphql::Value::from("INTERNAL")));
+
+        let resp = schema.execute(Request::new("{ value }").data(1u32)).await;
+        assert!(resp.errors.is_empty());
     }
 }
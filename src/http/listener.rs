@@ -0,0 +1,198 @@
+use std::io;
+use std::net::SocketAddr;
+#[cfg(unix)]
+use std::net::{IpAddr, Ipv4Addr};
+#[cfg(unix)]
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+#[cfg(unix)]
+use anyhow::{bail, Context as _};
+use arc_swap::ArcSwap;
+use axum::extract::connect_info::Connected;
+use hyper::server::accept::Accept;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::{TcpListener, TcpStream};
+#[cfg(unix)]
+use tokio::net::{UnixListener, UnixStream};
+use tokio::sync::mpsc;
+use tokio_rustls::rustls::ServerConfig;
+use tokio_rustls::server::TlsStream;
+use tokio_rustls::TlsAcceptor;
+use tracing::{debug, error};
+
+/// 没有在这个时间内完成握手的连接会被断开
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// 带有对端地址的连接，使`ConnectInfo<SocketAddr>`在非`AddrStream`的连接上也可以使用
+pub struct PeerStream<S> {
+    inner: S,
+    peer: SocketAddr,
+}
+
+impl<S> Connected<&PeerStream<S>> for SocketAddr {
+    fn connect_info(target: &PeerStream<S>) -> Self {
+        target.peer
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for PeerStream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_read(cx, buf)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for PeerStream<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().inner).poll_write_vectored(cx, bufs)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.inner.is_write_vectored()
+    }
+}
+
+/// 通过channel接收连接的`Accept`，连接由单独的task接受
+pub struct Incoming<S> {
+    rx: mpsc::Receiver<PeerStream<S>>,
+}
+
+impl<S> Accept for Incoming<S> {
+    type Conn = PeerStream<S>;
+    type Error = io::Error;
+
+    fn poll_accept(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Self::Conn, Self::Error>>> {
+        self.get_mut().rx.poll_recv(cx).map(|conn| conn.map(Ok))
+    }
+}
+
+/// 接受TCP连接并完成TLS握手，每次握手都会使用`config`中最新的证书
+/// 每个握手都在单独的task中进行，慢的客户端不会阻塞其他连接
+pub fn tls(
+    listener: TcpListener,
+    config: Arc<ArcSwap<ServerConfig>>,
+) -> Incoming<TlsStream<TcpStream>> {
+    let (tx, rx) = mpsc::channel(64);
+    tokio::spawn(async move {
+        loop {
+            let (stream, peer) = tokio::select! {
+                // 服务器已经关闭
+                _ = tx.closed() => break,
+                res = listener.accept() => match accepted(res).await {
+                    Some(conn) => conn,
+                    None => continue,
+                },
+            };
+            stream.set_nodelay(true).ok();
+            let acceptor = TlsAcceptor::from(config.load_full());
+            let tx = tx.clone();
+            tokio::spawn(async move {
+                match tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                    Ok(Ok(inner)) => {
+                        tx.send(PeerStream { inner, peer }).await.ok();
+                    }
+                    Ok(Err(err)) => debug!("与{}的TLS握手失败: {}", peer, err),
+                    Err(_) => debug!("与{}的TLS握手超时", peer),
+                }
+            });
+        }
+    });
+    Incoming { rx }
+}
+
+/// Unix domain socket的对端没有ip地址，视为本机
+#[cfg(unix)]
+const UNIX_PEER: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0);
+
+/// 监听Unix domain socket，删除上次没有正常退出时留下的socket文件
+#[cfg(unix)]
+pub fn bind_unix(path: &Path, mode: u32) -> anyhow::Result<UnixListener> {
+    use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+
+    if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+        std::fs::create_dir_all(parent)?;
+    }
+    if let Ok(metadata) = std::fs::symlink_metadata(path) {
+        if !metadata.file_type().is_socket() {
+            bail!("{}已存在并且不是socket文件", path.display());
+        }
+        if std::os::unix::net::UnixStream::connect(path).is_ok() {
+            bail!("{}正在被其他进程使用", path.display());
+        }
+        std::fs::remove_file(path)?;
+    }
+    let listener =
+        UnixListener::bind(path).with_context(|| format!("无法监听{}", path.display()))?;
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))?;
+    Ok(listener)
+}
+
+#[cfg(unix)]
+pub fn unix(listener: UnixListener) -> Incoming<UnixStream> {
+    let (tx, rx) = mpsc::channel(64);
+    tokio::spawn(async move {
+        loop {
+            let inner = tokio::select! {
+                _ = tx.closed() => break,
+                res = listener.accept() => match accepted(res).await {
+                    Some((stream, _)) => stream,
+                    None => continue,
+                },
+            };
+            if tx
+                .send(PeerStream {
+                    inner,
+                    peer: UNIX_PEER,
+                })
+                .await
+                .is_err()
+            {
+                break;
+            }
+        }
+    });
+    Incoming { rx }
+}
+
+/// 接受连接失败一般是文件描述符耗尽之类的暂时性错误，等待一段时间后重试
+/// 直接返回错误会导致hyper关闭整个服务器
+async fn accepted<T>(res: io::Result<T>) -> Option<T> {
+    match res {
+        Ok(conn) => Some(conn),
+        Err(err) => {
+            error!("接受连接失败: {}", err);
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            None
+        }
+    }
+}
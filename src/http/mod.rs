@@ -1,34 +1,141 @@
-use axum::routing::get;
-use axum::Router;
-use axum_sessions::SessionLayer;
-use rand::Rng;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use anyhow::Context;
+use arc_swap::ArcSwap;
+use axum::routing::{get, post};
+use axum::{middleware, Extension, Router};
+use futures::future::BoxFuture;
+use tokio::net::TcpListener;
 use tokio_graceful_shutdown::SubsystemHandle;
 use tracing::info;
 
-use crate::configure::get_config;
-use crate::http::rocksdb_session_store::RocksdbStore;
+use crate::configure::{get_config, Config, ListenAddr};
+use crate::http::rate_limit::RateLimiter;
+use crate::http::rocksdb_session_store::{session_gc, RocksdbStore};
+use crate::http::session_secret::RotatingSessionLayer;
 
+mod auth;
+mod client_info;
 mod error;
+mod listener;
 mod model;
-mod rocksdb_session_store;
+mod rate_limit;
+mod request_id;
+pub mod rocksdb_session_store;
 mod routes;
+pub mod session_secret;
+mod tls;
+mod trace;
 mod ws;
 
 pub async fn start_http_server(handle: SubsystemHandle) -> anyhow::Result<()> {
+    let store = RocksdbStore::new()?;
+    let gc_store = store.clone();
+    handle.start("session gc", move |handle| session_gc(gc_store, handle));
+    let rate_limiter = RateLimiter::new(store.clone());
+    let session_layer = RotatingSessionLayer::new(store)?;
+
+    // 需要登录才能访问的路由
+    let protected = Router::new()
+        .route("/ws", get(ws::ws_route))
+        .route_layer(middleware::from_fn(auth::require_login));
+
     let app = Router::new()
         .route("/", get(routes::hello_world))
-        .route("/ws", get(ws::ws_route))
-        .layer(SessionLayer::new(
-            RocksdbStore::new()?,
-            &rand::thread_rng().gen::<[u8; 64]>(),
-        ));
-
-    axum::Server::bind(&get_config().http.bind)
-        .serve(app.into_make_service())
-        .with_graceful_shutdown(async move {
-            handle.on_shutdown_requested().await;
-            info!("http server is shutting down...");
-        })
-        .await
-        .map_err(Into::into)
+        .route("/login", post(routes::login))
+        .route("/logout", post(routes::logout))
+        // graphql的登录检查由各个字段上的`LoginGuard`负责，因为登录本身也是通过graphql完成的
+        .route("/graphql", get(routes::graphiql).post(routes::graphql))
+        .merge(protected)
+        .fallback(routes::not_found)
+        .layer(Extension(model::build_schema()))
+        .layer(Extension(session_layer.clone()))
+        .layer(session_layer)
+        .layer(middleware::from_fn(rate_limit::limit_requests))
+        .layer(Extension(rate_limiter))
+        .layer(middleware::from_fn(trace::trace_request))
+        .layer(middleware::from_fn(client_info::resolve_client_ip))
+        .layer(middleware::from_fn(request_id::set_request_id));
+
+    let config = Config::clone(&get_config());
+    let make_service = app.into_make_service_with_connect_info::<SocketAddr>();
+
+    // TLS只用于TCP监听地址，Unix domain socket一般在反向代理后面，由反向代理负责TLS
+    let https_port = config.http.tcp_binds().next().map(|addr| addr.port());
+    let server_config = match https_port {
+        Some(https_port) if config.http.tls.enabled => {
+            let server_config = Arc::new(ArcSwap::from_pointee(tls::load_server_config(&config)?));
+            let (cert, key) = tls::cert_paths(&config);
+            let watched = server_config.clone();
+            handle.start("tls cert watcher", move |handle| {
+                tls::watch_certificates(watched, cert, key, handle)
+            });
+            if let Some(redirect) = config.http.tls.redirect_bind {
+                handle.start("https redirect", move |handle| {
+                    tls::redirect_to_https(redirect, https_port, handle)
+                });
+            }
+            Some(server_config)
+        }
+        _ => None,
+    };
+
+    // 先绑定全部地址再开始服务，任何一个地址绑定失败都不会启动
+    let mut servers: Vec<BoxFuture<'_, hyper::Result<()>>> = Vec::new();
+    let mut sockets = Vec::new();
+    for addr in &config.http.bind {
+        let shutdown = shutdown(&handle, addr);
+        match addr {
+            ListenAddr::Tcp(addr) => match &server_config {
+                Some(server_config) => {
+                    let listener = TcpListener::bind(addr)
+                        .await
+                        .with_context(|| format!("无法监听{}", addr))?;
+                    info!("https listening on {}", addr);
+                    servers.push(Box::pin(
+                        hyper::Server::builder(listener::tls(listener, server_config.clone()))
+                            .serve(make_service.clone())
+                            .with_graceful_shutdown(shutdown),
+                    ));
+                }
+                None => {
+                    let server = axum::Server::try_bind(addr)
+                        .with_context(|| format!("无法监听{}", addr))?;
+                    info!("http listening on {}", addr);
+                    servers.push(Box::pin(
+                        server
+                            .serve(make_service.clone())
+                            .with_graceful_shutdown(shutdown),
+                    ));
+                }
+            },
+            #[cfg(unix)]
+            ListenAddr::Unix(path) => {
+                let path = config.storage.resolve(path);
+                let listener = listener::bind_unix(&path, config.http.unix_socket_mode)?;
+                info!("http listening on unix:{}", path.display());
+                sockets.push(path);
+                servers.push(Box::pin(
+                    hyper::Server::builder(listener::unix(listener))
+                        .serve(make_service.clone())
+                        .with_graceful_shutdown(shutdown),
+                ));
+            }
+            #[cfg(not(unix))]
+            ListenAddr::Unix(_) => anyhow::bail!("当前平台不支持Unix domain socket"),
+        }
+    }
+
+    let result = futures::future::try_join_all(servers).await;
+    for socket in sockets {
+        std::fs::remove_file(socket).ok();
+    }
+    result?;
+    Ok(())
+}
+
+async fn shutdown(handle: &SubsystemHandle, addr: &ListenAddr) {
+    handle.on_shutdown_requested().await;
+    info!("http server on {} is shutting down...", addr);
 }
@@ -0,0 +1,146 @@
+use async_graphql::{Context, Guard, GuardExt, Object, SimpleObject};
+use axum_sessions::SessionHandle;
+use chrono::{DateTime, Utc};
+use sea_orm::EntityTrait;
+
+use crate::database::entity::user;
+use crate::database::get_database;
+use crate::http::auth::{self, CurrentUser};
+use crate::http::client_info::ClientInfo;
+use crate::http::error::{ctx_data, Error, ErrorKind};
+use crate::http::rate_limit::RateLimiter;
+use crate::http::session_secret::RotatingSessionLayer;
+use crate::user::ADMIN_USERNAME;
+
+/// 要求已经登录的guard
+/// 是否登录取决于请求的data里有没有`CurrentUser`
+pub struct LoginGuard;
+
+#[async_trait::async_trait]
+impl Guard for LoginGuard {
+    async fn check(&self, ctx: &Context<'_>) -> async_graphql::Result<()> {
+        ctx.data_opt::<CurrentUser>()
+            .map(|_| ())
+            .ok_or_else(|| Error::new(ErrorKind::Unauthorized, "需要登录").into())
+    }
+}
+
+/// 要求通过HTTP请求执行的guard
+/// 登录状态保存在cookie对应的session里，websocket上的操作没有`SessionHandle`，无法读取或修改session
+pub struct HttpOnlyGuard;
+
+#[async_trait::async_trait]
+impl Guard for HttpOnlyGuard {
+    async fn check(&self, ctx: &Context<'_>) -> async_graphql::Result<()> {
+        ctx.data_opt::<SessionHandle>()
+            .map(|_| ())
+            .ok_or_else(|| Error::new(ErrorKind::BadRequest, "这个操作只能通过HTTP请求执行").into())
+    }
+}
+
+/// 要求当前用户是初始管理员账号的guard，在有角色之前用于限制影响全部用户的操作
+pub struct AdminGuard;
+
+#[async_trait::async_trait]
+impl Guard for AdminGuard {
+    async fn check(&self, ctx: &Context<'_>) -> async_graphql::Result<()> {
+        LoginGuard.check(ctx).await?;
+        let id = ctx_data::<CurrentUser>(ctx)?.id;
+        let user = user::Entity::find_by_id(id)
+            .one(get_database())
+            .await
+            .map_err(Error::from)?;
+        match user {
+            Some(user) if user.username == ADMIN_USERNAME => Ok(()),
+            _ => Err(Error::new(ErrorKind::Forbidden, "只有管理员可以执行这个操作").into()),
+        }
+    }
+}
+
+#[derive(SimpleObject)]
+pub struct UserInfo {
+    id: i32,
+    /// 用户名
+    username: String,
+    /// 创建时间
+    created_at: DateTime<Utc>,
+}
+
+impl From<user::Model> for UserInfo {
+    fn from(user: user::Model) -> Self {
+        UserInfo {
+            id: user.id,
+            username: user.username,
+            created_at: user.created_at,
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct AuthQuery;
+
+#[Object]
+impl AuthQuery {
+    /// 当前登录的用户，未登录时为null
+    async fn me(&self, ctx: &Context<'_>) -> async_graphql::Result<Option<UserInfo>> {
+        let id = match ctx.data_opt::<CurrentUser>() {
+            Some(user) => user.id,
+            None => return Ok(None),
+        };
+        Ok(user::Entity::find_by_id(id)
+            .one(get_database())
+            .await
+            .map_err(Error::from)?
+            .map(Into::into))
+    }
+}
+
+#[derive(Default)]
+pub struct AuthMutation;
+
+#[Object]
+impl AuthMutation {
+    /// 使用用户名和密码登录，登录状态保存在cookie对应的session里
+    #[graphql(guard = "HttpOnlyGuard")]
+    async fn login(
+        &self,
+        ctx: &Context<'_>,
+        username: String,
+        password: String,
+    ) -> async_graphql::Result<UserInfo> {
+        let client = ctx_data::<ClientInfo>(ctx)?;
+        let limiter = ctx_data::<RateLimiter>(ctx)?;
+        let store = ctx_data::<RotatingSessionLayer>(ctx)?.store();
+        let mut session = ctx_data::<SessionHandle>(ctx)?.write().await;
+        auth::login(limiter, store, &mut session, client, &username, password)
+            .await?
+            .map(Into::into)
+            .ok_or_else(|| Error::new(ErrorKind::Unauthorized, "用户名或密码错误").into())
+    }
+
+    /// 退出登录
+    #[graphql(guard = "HttpOnlyGuard")]
+    async fn logout(&self, ctx: &Context<'_>) -> async_graphql::Result<bool> {
+        auth::logout(&mut *ctx_data::<SessionHandle>(ctx)?.write().await);
+        Ok(true)
+    }
+
+    /// 轮换session cookie的签名密钥
+    /// `logoutAll`为true时所有用户(包括自己)都会被强制退出登录，否则旧密钥签名的cookie仍然有效
+    #[graphql(guard = "HttpOnlyGuard.and(AdminGuard)")]
+    async fn rotate_session_secret(
+        &self,
+        ctx: &Context<'_>,
+        #[graphql(default = true)] logout_all: bool,
+    ) -> async_graphql::Result<bool> {
+        ctx_data::<RotatingSessionLayer>(ctx)?
+            .rotate(logout_all)
+            .await
+            .map_err(Error::from)?;
+        if logout_all {
+            // 当前请求结束时session会被重新保存，需要主动销毁
+            auth::logout(&mut *ctx_data::<SessionHandle>(ctx)?.write().await);
+        }
+        Ok(true)
+    }
+}
@@ -0,0 +1,201 @@
+use async_graphql::{Enum, Json, Object, SimpleObject};
+use chrono::{DateTime, Utc};
+use serde_json::Value;
+
+use crate::configure::{
+    self, get_config, ConfigChange, ConfigSource, ConfigVersion, MergeOutcome, Provenance,
+};
+use crate::http::error::{Error, ErrorKind};
+use crate::http::model::auth::{AdminGuard, LoginGuard};
+
+/// 一个配置项的变化
+#[derive(SimpleObject)]
+pub struct ConfigChangeInfo {
+    /// 以`.`分隔的完整路径，例如`http.bind`
+    key: String,
+    old: Option<Json<Value>>,
+    new: Option<Json<Value>>,
+}
+
+impl From<ConfigChange> for ConfigChangeInfo {
+    fn from(change: ConfigChange) -> Self {
+        ConfigChangeInfo {
+            key: change.key,
+            old: change.old.map(Json),
+            new: change.new.map(Json),
+        }
+    }
+}
+
+#[derive(SimpleObject)]
+pub struct UpdateConfigResult {
+    changes: Vec<ConfigChangeInfo>,
+    /// 有变化并且需要重启才能生效的配置项
+    restart_required: Vec<String>,
+    /// 不影响配置生效的问题
+    warnings: Vec<String>,
+}
+
+impl From<MergeOutcome> for UpdateConfigResult {
+    fn from(outcome: MergeOutcome) -> Self {
+        UpdateConfigResult {
+            changes: outcome.changes.into_iter().map(Into::into).collect(),
+            restart_required: outcome
+                .restart_required
+                .into_iter()
+                .map(Into::into)
+                .collect(),
+            warnings: outcome.warnings.iter().map(ToString::to_string).collect(),
+        }
+    }
+}
+
+/// `_config_auto.json`的一个历史版本
+#[derive(SimpleObject)]
+pub struct ConfigVersionInfo {
+    id: String,
+    /// 被新版本替换的时间
+    saved_at: DateTime<Utc>,
+}
+
+impl From<ConfigVersion> for ConfigVersionInfo {
+    fn from(version: ConfigVersion) -> Self {
+        ConfigVersionInfo {
+            id: version.id,
+            saved_at: version.saved_at,
+        }
+    }
+}
+
+#[derive(Enum, Copy, Clone, Eq, PartialEq)]
+pub enum ConfigSourceKind {
+    Default,
+    AutoConfig,
+    ConfigFile,
+    Env,
+    CommandLine,
+    /// 运行时修改并且没有持久化
+    Runtime,
+}
+
+/// 一个配置项当前的值和来源
+#[derive(SimpleObject)]
+pub struct ConfigProvenance {
+    key: String,
+    value: Json<Value>,
+    kind: ConfigSourceKind,
+    /// 文件路径或者环境变量名
+    location: Option<String>,
+    /// 便于阅读的来源描述
+    description: String,
+}
+
+impl From<Provenance> for ConfigProvenance {
+    fn from(entry: Provenance) -> Self {
+        let description = entry.source.to_string();
+        let (kind, location) = match entry.source {
+            ConfigSource::Default => (ConfigSourceKind::Default, None),
+            ConfigSource::AutoConfig(path) => (
+                ConfigSourceKind::AutoConfig,
+                Some(path.display().to_string()),
+            ),
+            ConfigSource::ConfigFile(path) => (
+                ConfigSourceKind::ConfigFile,
+                Some(path.display().to_string()),
+            ),
+            ConfigSource::Env(name) => (ConfigSourceKind::Env, Some(name)),
+            ConfigSource::CommandLine => (ConfigSourceKind::CommandLine, None),
+            ConfigSource::Runtime => (ConfigSourceKind::Runtime, None),
+        };
+        ConfigProvenance {
+            key: entry.key,
+            value: Json(entry.value),
+            kind,
+            location,
+            description,
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct ConfigQuery;
+
+#[Object]
+impl ConfigQuery {
+    /// 当前生效的配置
+    #[graphql(guard = "LoginGuard")]
+    async fn config(&self) -> async_graphql::Result<Json<Value>> {
+        Ok(Json(
+            serde_json::to_value(&**get_config()).map_err(Error::from)?,
+        ))
+    }
+
+    /// 每个配置项的值来自哪里
+    #[graphql(guard = "LoginGuard")]
+    async fn config_provenance(&self) -> async_graphql::Result<Vec<ConfigProvenance>> {
+        Ok(configure::provenance()
+            .map_err(Error::from)?
+            .into_iter()
+            .map(Into::into)
+            .collect())
+    }
+
+    /// 通过webui持久化的配置的历史版本，新的在前
+    #[graphql(guard = "LoginGuard")]
+    async fn config_history(&self) -> async_graphql::Result<Vec<ConfigVersionInfo>> {
+        Ok(configure::history()
+            .map_err(Error::from)?
+            .into_iter()
+            .map(Into::into)
+            .collect())
+    }
+}
+
+#[derive(Default)]
+pub struct ConfigMutation;
+
+#[Object]
+impl ConfigMutation {
+    /// 将`patch`合并到当前配置，例如`{"http": {"bind": "0.0.0.0:8686"}}`
+    /// `persist`为true时只把`patch`合并到`_config_auto.json`，重启后仍然有效
+    /// 否则重新加载配置后仍然有效，重启后丢失
+    /// 配置影响全部用户，例如信任的代理、速率限制和数据目录，所以只有管理员可以修改
+    #[graphql(guard = "AdminGuard")]
+    async fn update_config(
+        &self,
+        patch: Json<Value>,
+        #[graphql(default = false)] persist: bool,
+    ) -> async_graphql::Result<UpdateConfigResult> {
+        // 校验和持久化会读写文件、绑定端口，不能在异步任务中直接执行
+        Ok(
+            tokio::task::spawn_blocking(move || configure::merge(patch.0, persist))
+                .await
+                .map_err(Error::from)?
+                .map_err(Error::from)?
+                .into(),
+        )
+    }
+
+    /// 恢复到某个历史版本并丢弃没有持久化的修改，当前版本同样会被保存到历史中
+    #[graphql(guard = "AdminGuard")]
+    async fn rollback_config(&self, id: String) -> async_graphql::Result<UpdateConfigResult> {
+        let version = id.clone();
+        let outcome =
+            tokio::task::spawn_blocking(move || -> anyhow::Result<Option<MergeOutcome>> {
+                if !configure::history()?
+                    .iter()
+                    .any(|history| history.id == version)
+                {
+                    return Ok(None);
+                }
+                configure::rollback(&version).map(Some)
+            })
+            .await
+            .map_err(Error::from)?
+            .map_err(Error::from)?;
+        match outcome {
+            Some(outcome) => Ok(outcome.into()),
+            None => Err(Error::new(ErrorKind::NotFound, format!("配置版本{}不存在", id)).into()),
+        }
+    }
+}
@@ -0,0 +1,248 @@
+use async_graphql::connection::{Connection, Edge};
+use async_graphql::{Enum, InputObject, Json, Object, SimpleObject};
+use chrono::{DateTime, Utc};
+use serde_json::{json, Map, Value};
+use tracing::Level;
+
+use crate::configure::{self, get_config};
+use crate::http::error::{Error, ErrorKind};
+use crate::http::model::auth::AdminGuard;
+use crate::http::model::config::UpdateConfigResult;
+use crate::log::query::{self, LogCursor, LogFilter, SpanField};
+use crate::log::{writer, ACCESS_LOG_FILE_PREFIX, LOG_FILE_PREFIX};
+
+const MAX_PAGE_SIZE: usize = 1000;
+
+#[derive(Enum, Copy, Clone, Eq, PartialEq)]
+pub enum LogLevel {
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl From<Level> for LogLevel {
+    fn from(level: Level) -> Self {
+        match level {
+            Level::ERROR => LogLevel::Error,
+            Level::WARN => LogLevel::Warn,
+            Level::INFO => LogLevel::Info,
+            Level::DEBUG => LogLevel::Debug,
+            Level::TRACE => LogLevel::Trace,
+        }
+    }
+}
+
+impl From<LogLevel> for Level {
+    fn from(level: LogLevel) -> Self {
+        match level {
+            LogLevel::Error => Level::ERROR,
+            LogLevel::Warn => Level::WARN,
+            LogLevel::Info => Level::INFO,
+            LogLevel::Debug => Level::DEBUG,
+            LogLevel::Trace => Level::TRACE,
+        }
+    }
+}
+
+#[derive(Enum, Copy, Clone, Eq, PartialEq, Default)]
+pub enum LogSource {
+    /// 面板的运行日志
+    #[default]
+    Panel,
+    /// http访问日志
+    Access,
+}
+
+/// span中某个字段等于`value`，例如`{name: "request_id", value: "..."}`
+#[derive(InputObject)]
+pub struct SpanFieldInput {
+    name: String,
+    value: String,
+}
+
+#[derive(InputObject, Default)]
+pub struct LogFilterInput {
+    since: Option<DateTime<Utc>>,
+    until: Option<DateTime<Utc>>,
+    /// 只包含这个等级以及更严重的日志
+    level: Option<LogLevel>,
+    /// 模块路径，同时包含子模块的日志，例如`cat_panel_backend::http`
+    target: Option<String>,
+    /// 任意一层span中有这个字段
+    span_field: Option<SpanFieldInput>,
+    /// 在整行json中搜索
+    contains: Option<String>,
+}
+
+impl From<LogFilterInput> for LogFilter {
+    fn from(input: LogFilterInput) -> Self {
+        LogFilter {
+            since: input.since,
+            until: input.until,
+            level: input.level.map(Into::into),
+            target: input.target,
+            span_field: input.span_field.map(|field| SpanField {
+                name: field.name,
+                value: field.value,
+            }),
+            contains: input.contains,
+        }
+    }
+}
+
+/// 日志文件中的一条日志
+#[derive(SimpleObject)]
+pub struct LogEntry {
+    timestamp: DateTime<Utc>,
+    level: LogLevel,
+    target: String,
+    message: Option<String>,
+    /// 从外到内的span和它们的字段
+    spans: Json<Vec<Value>>,
+    /// 其他字段，例如事件的字段、代码位置和线程
+    fields: Json<Map<String, Value>>,
+}
+
+impl From<query::LogEntry> for LogEntry {
+    fn from(entry: query::LogEntry) -> Self {
+        let spans = entry.spans().cloned().map(Value::Object).collect();
+        let mut fields = entry.fields;
+        fields.remove("spans");
+        fields.remove("span");
+        let message = match fields.remove("message") {
+            Some(Value::String(message)) => Some(message),
+            Some(message) => Some(message.to_string()),
+            None => None,
+        };
+        LogEntry {
+            timestamp: entry.timestamp,
+            level: entry.level.into(),
+            target: entry.target,
+            message,
+            spans: Json(spans),
+            fields: Json(fields),
+        }
+    }
+}
+
+#[derive(SimpleObject)]
+pub struct LogConnectionFields {
+    /// 下一页的`after`，没有下一页时为null
+    /// 返回的日志不满一页时它不等于`pageInfo.endCursor`，需要从这里继续查询
+    next_cursor: Option<String>,
+}
+
+/// 日志文件写入器启动以来的统计
+#[derive(SimpleObject)]
+pub struct LogWriterStats {
+    source: LogSource,
+    /// 已经写入文件的行数
+    written_lines: u64,
+    /// 因为队列已满而丢弃的行数，`log.overflow`为`block`时只有单线程运行时中的日志会被丢弃
+    dropped_lines: u64,
+}
+
+#[derive(Default)]
+pub struct LogQuery;
+
+#[Object]
+impl LogQuery {
+    /// 按时间从新到旧查询日志文件，包括已经切换和压缩的文件
+    /// 下一页使用上一页的`nextCursor`作为`after`，一次读取的日志过多时会返回不满一页的结果
+    /// 日志中可能有客户端ip、路径之类的敏感信息，只有管理员可以查询
+    #[graphql(guard = "AdminGuard")]
+    async fn logs(
+        &self,
+        #[graphql(default)] source: LogSource,
+        #[graphql(default)] filter: LogFilterInput,
+        after: Option<String>,
+        #[graphql(default = 100)] first: usize,
+    ) -> async_graphql::Result<Connection<String, LogEntry, LogConnectionFields>> {
+        if !(1..=MAX_PAGE_SIZE).contains(&first) {
+            return Err(Error::new(
+                ErrorKind::Validation,
+                format!("first必须在1到{}之间", MAX_PAGE_SIZE),
+            )
+            .into());
+        }
+        let after = after
+            .map(|after| after.parse::<LogCursor>())
+            .transpose()
+            .map_err(|err| Error::new(ErrorKind::BadRequest, err.to_string()))?;
+        let prefix = match source {
+            LogSource::Panel => LOG_FILE_PREFIX,
+            LogSource::Access => ACCESS_LOG_FILE_PREFIX,
+        };
+
+        let dir = get_config().storage.logs();
+        let filter = filter.into();
+        let page =
+            tokio::task::spawn_blocking(move || query::query(&dir, prefix, &filter, after, first))
+                .await
+                .map_err(Error::from)?
+                .map_err(Error::from)?;
+
+        let mut connection = Connection::with_additional_fields(
+            after.is_some(),
+            page.next_cursor.is_some(),
+            LogConnectionFields {
+                next_cursor: page.next_cursor.map(|cursor| cursor.to_string()),
+            },
+        );
+        connection.edges.extend(
+            page.entries
+                .into_iter()
+                .map(|(cursor, entry)| Edge::new(cursor.to_string(), entry.into())),
+        );
+        Ok(connection)
+    }
+
+    #[graphql(guard = "AdminGuard")]
+    async fn log_writer_stats(&self) -> Vec<LogWriterStats> {
+        writer::stats()
+            .into_iter()
+            .map(|stats| LogWriterStats {
+                source: match stats.prefix {
+                    ACCESS_LOG_FILE_PREFIX => LogSource::Access,
+                    _ => LogSource::Panel,
+                },
+                written_lines: stats.written_lines,
+                dropped_lines: stats.dropped_lines,
+            })
+            .collect()
+    }
+}
+
+#[derive(Default)]
+pub struct LogMutation;
+
+#[Object]
+impl LogMutation {
+    /// 修改控制台和日志文件的过滤规则，格式与`RUST_LOG`相同，例如`info,sea_orm=warn`
+    /// 没有指定的保持不变，`persist`的含义与`updateConfig`相同，同样只有管理员可以修改
+    #[graphql(guard = "AdminGuard")]
+    async fn set_log_filters(
+        &self,
+        console: Option<String>,
+        file: Option<String>,
+        #[graphql(default = false)] persist: bool,
+    ) -> async_graphql::Result<UpdateConfigResult> {
+        let mut log = Map::new();
+        if let Some(console) = console {
+            log.insert("level".to_owned(), Value::String(console));
+        }
+        if let Some(file) = file {
+            log.insert("file_level".to_owned(), Value::String(file));
+        }
+        // 与`updateConfig`一样，持久化会同步写文件
+        Ok(
+            tokio::task::spawn_blocking(move || configure::merge(json!({ "log": log }), persist))
+                .await
+                .map_err(Error::from)?
+                .map_err(Error::from)?
+                .into(),
+        )
+    }
+}
@@ -1,19 +1,56 @@
-use crate::http::model::system_info::SystemInfo;
-use async_graphql::{EmptyMutation, EmptySubscription, Object, Schema};
+use crate::http::model::auth::{AuthMutation, AuthQuery, LoginGuard};
+use crate::http::model::config::{ConfigMutation, ConfigQuery};
+use crate::http::model::log::{LogMutation, LogQuery};
+use crate::http::model::session::{SessionMutation, SessionQuery};
+use crate::http::model::subscription::Subscription;
+use crate::http::model::system_info::{LimitedRefreshSystem, SystemInfo};
+use async_graphql::{MergedObject, Object, Schema, SchemaBuilder};
 
+mod auth;
+mod config;
+mod log;
+mod session;
+mod subscription;
 mod system_info;
 
-pub struct Query;
+pub type AppSchema = Schema<Query, Mutation, Subscription>;
+
+#[derive(MergedObject, Default)]
+pub struct Query(
+    SystemInfoQuery,
+    AuthQuery,
+    SessionQuery,
+    ConfigQuery,
+    LogQuery,
+);
+
+#[derive(MergedObject, Default)]
+pub struct Mutation(AuthMutation, SessionMutation, ConfigMutation, LogMutation);
+
+#[derive(Default)]
+pub struct SystemInfoQuery;
 
 #[Object]
-impl Query {
+impl SystemInfoQuery {
+    #[graphql(guard = "LoginGuard")]
     async fn system_info(&self) -> SystemInfo {
         SystemInfo::default()
     }
 }
 
-pub fn schema() -> Schema<Query, EmptyMutation, EmptySubscription> {
-    Schema::build(Query, EmptyMutation, EmptySubscription).finish()
+fn schema_builder() -> SchemaBuilder<Query, Mutation, Subscription> {
+    Schema::build(Query::default(), Mutation::default(), Subscription)
+}
+
+/// 不带任何运行时数据的schema，只用于导出sdl等不需要执行查询的场景
+pub fn schema() -> AppSchema {
+    schema_builder().finish()
+}
+
+/// 创建http服务器使用的schema
+/// 所有resolver共享同一个`LimitedRefreshSystem`，刷新频率限制才能对全部请求生效
+pub fn build_schema() -> AppSchema {
+    schema_builder().data(LimitedRefreshSystem::new()).finish()
 }
 
 #[test]
@@ -23,11 +60,201 @@ fn print_sdl() {
 
 #[cfg(test)]
 mod tests {
-    use crate::http::model::schema;
+    use std::io::Write;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use async_graphql::{Data, Executor, Request};
+    use axum_sessions::async_session::{Session, SessionStore};
+    use chrono::Utc;
+    use futures::StreamExt;
+    use serde_json::json;
+
+    use crate::database::entity::user;
+    use crate::http::auth::{
+        CurrentUser, SessionWatch, SESSION_CREDENTIAL_VERSION, SESSION_LOGIN_AT, SESSION_USER_ID,
+    };
+    use crate::http::model::build_schema;
+    use crate::http::rocksdb_session_store::RocksdbStore;
+    use crate::http::ws::SessionExecutor;
+    use crate::log::broadcast;
+    use crate::user::ADMIN_USERNAME;
+
+    #[tokio::test]
+    async fn test_system_info() -> anyhow::Result<()> {
+        let _config = crate::configure::init_test_configure()?;
+        let query = "{ systemInfo { memory { totalMemory } cpus { name } } }";
+        let schema = build_schema();
+
+        // 未登录时应该被guard拒绝
+        let resp = schema.execute(query).await;
+        assert_eq!(resp.errors.len(), 1);
+
+        let resp = schema
+            .execute(Request::new(query).data(CurrentUser { id: 1 }))
+            .await;
+        assert!(resp.errors.is_empty(), "{:?}", resp.errors);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_memory_subscription() -> anyhow::Result<()> {
+        let _config = crate::configure::init_test_configure()?;
+        let mut stream = build_schema().execute_stream(
+            Request::new("subscription { memory(interval: 0) { totalMemory } }")
+                .data(CurrentUser { id: 1 }),
+        );
+        let resp = stream.next().await.unwrap();
+        assert!(resp.errors.is_empty(), "{:?}", resp.errors);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_admin_only() -> anyhow::Result<()> {
+        let _config = crate::configure::init_test_configure()?;
+        let db = crate::database::init_test_database().await?;
+        let user = match crate::user::find_by_username(db, "not_admin").await? {
+            Some(user) => user,
+            None => crate::user::create_user(db, "not_admin", "password".to_owned()).await?,
+        };
+
+        // 修改配置会影响全部用户，日志里可能有敏感信息，普通用户都不能执行
+        for query in [
+            r#"mutation { updateConfig(patch: { http: { trusted_proxies: ["0.0.0.0/0"] } }, persist: true) { restartRequired } }"#,
+            r#"mutation { rollbackConfig(id: "0") { restartRequired } }"#,
+            r#"mutation { setLogFilters(console: "trace", persist: true) { restartRequired } }"#,
+            r#"{ logs(first: 1) { edges { node { message } } } }"#,
+            r#"{ logWriterStats { writtenLines } }"#,
+        ] {
+            let resp = build_schema()
+                .execute(Request::new(query).data(CurrentUser { id: user.id }))
+                .await;
+            let code = resp.errors[0].extensions.as_ref().unwrap().get("code");
+            assert_eq!(
+                code,
+                Some(&async_graphql::Value::from("FORBIDDEN")),
+                "{}",
+                query
+            );
+        }
+        Ok(())
+    }
+
+    /// 以管理员身份登录并返回websocket连接上使用的executor
+    /// `AdminGuard`使用全局的数据库
+    async fn admin_executor(
+        store: &RocksdbStore,
+    ) -> anyhow::Result<(SessionExecutor, user::Model, Session)> {
+        let db = crate::database::init_test_database().await?;
+        let user = match crate::user::find_by_username(db, ADMIN_USERNAME).await? {
+            Some(user) => user,
+            None => crate::user::create_user(db, ADMIN_USERNAME, "password".to_owned()).await?,
+        };
+
+        let mut session = Session::new();
+        session.insert(SESSION_USER_ID, user.id)?;
+        session.insert(
+            SESSION_CREDENTIAL_VERSION,
+            crate::user::credential_version(&user),
+        )?;
+        session.insert(SESSION_LOGIN_AT, Utc::now().timestamp())?;
+        store.store_session(session.clone()).await?;
+        let watch = SessionWatch::new(store.clone(), db.clone(), session.id().to_owned(), user.id);
+        Ok((SessionExecutor::new(build_schema(), watch), user, session))
+    }
+
+    #[tokio::test]
+    async fn test_logs_subscription_revoked() -> anyhow::Result<()> {
+        let _config = crate::configure::init_test_configure()?;
+        let dir = tempfile::tempdir()?;
+        let store = RocksdbStore::open(dir.path())?;
+        let (executor, user, session) = admin_executor(&store).await?;
+
+        let mut broadcast = broadcast::init(0);
+        let line = |message: &str| {
+            format!(
+                "{}\n",
+                json!({
+                    "timestamp": Utc::now().to_rfc3339(),
+                    "level": "INFO",
+                    "target": "cat_panel_backend",
+                    "message": message,
+                })
+            )
+        };
+        let mut stream = executor.execute_stream(
+            Request::new("subscription { logs(recent: 0) { message } }")
+                .data(CurrentUser { id: user.id }),
+            None,
+        );
+        // 订阅在第一次poll时才会开始
+        let first = tokio::spawn(async move {
+            let resp = stream.next().await.unwrap();
+            (resp, stream)
+        });
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        broadcast.write_all(line("before").as_bytes())?;
+        let (resp, mut stream) = first.await?;
+        assert!(resp.errors.is_empty(), "{:?}", resp.errors);
+        assert_eq!(
+            resp.data.into_json()?,
+            json!({ "logs": { "message": "before" } })
+        );
+
+        // session被撤销之后不再推送，返回错误后订阅结束
+        assert!(store.revoke_session(user.id, session.id())?);
+        broadcast.write_all(line("after").as_bytes())?;
+        let resp = stream.next().await.unwrap();
+        let code = resp.errors[0].extensions.as_ref().unwrap().get("code");
+        assert_eq!(code, Some(&async_graphql::Value::from("UNAUTHORIZED")));
+        assert!(stream.next().await.is_none());
+
+        // 同一个连接上的查询和修改也会被拒绝
+        let mut stream = executor.execute_stream(
+            Request::new("{ systemInfo { memory { totalMemory } } }")
+                .data(CurrentUser { id: user.id }),
+            None,
+        );
+        let resp = stream.next().await.unwrap();
+        let code = resp.errors[0].extensions.as_ref().unwrap().get("code");
+        assert_eq!(code, Some(&async_graphql::Value::from("UNAUTHORIZED")));
+        assert!(stream.next().await.is_none());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_http_only_over_ws() -> anyhow::Result<()> {
+        let _config = crate::configure::init_test_configure()?;
+        let dir = tempfile::tempdir()?;
+        let store = RocksdbStore::open(dir.path())?;
+        let (executor, user, session) = admin_executor(&store).await?;
+        // 和`ws::handle`一样，连接上只有`CurrentUser`
+        let mut data = Data::default();
+        data.insert(CurrentUser { id: user.id });
+        let data = Arc::new(data);
 
-    #[test]
-    #[ignore]
-    fn test_system_info() {
-        todo!()
+        // 读取或修改session的操作需要cookie，在websocket上明确拒绝而不是返回内部错误
+        for query in [
+            r#"mutation { login(username: "admin", password: "password") { id } }"#,
+            "mutation { logout }",
+            "mutation { rotateSessionSecret(logoutAll: false) }",
+            "{ sessions { id } }",
+            r#"mutation { revokeSession(sessionId: "0") }"#,
+            "mutation { revokeAllSessions }",
+        ] {
+            let mut stream = executor.execute_stream(Request::new(query), Some(data.clone()));
+            let resp = stream.next().await.unwrap();
+            let code = resp.errors[0].extensions.as_ref().unwrap().get("code");
+            assert_eq!(
+                code,
+                Some(&async_graphql::Value::from("BAD_REQUEST")),
+                "{}",
+                query
+            );
+        }
+        // session没有受到影响
+        let sessions = store.list_user_sessions(user.id)?;
+        assert!(sessions.iter().any(|meta| meta.id == session.id()));
+        Ok(())
     }
 }
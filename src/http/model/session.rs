@@ -0,0 +1,125 @@
+use async_graphql::{Context, GuardExt, Object, SimpleObject};
+use axum_sessions::SessionHandle;
+use chrono::{DateTime, Utc};
+
+use crate::http::auth::{self, CurrentUser};
+use crate::http::error::{ctx_data, Error, ErrorKind};
+use crate::http::model::auth::{HttpOnlyGuard, LoginGuard};
+use crate::http::rocksdb_session_store::SessionMeta;
+use crate::http::session_secret::RotatingSessionLayer;
+
+/// 一个已登录的session
+#[derive(SimpleObject)]
+pub struct SessionInfo {
+    id: String,
+    user_id: i32,
+    /// 登录时间
+    login_at: Option<DateTime<Utc>>,
+    /// 最后一次访问的时间
+    last_seen: Option<DateTime<Utc>>,
+    /// 登录时的客户端ip
+    ip: Option<String>,
+    /// 登录时的user agent
+    user_agent: Option<String>,
+    /// 是否为发起本次请求的session
+    current: bool,
+}
+
+impl SessionInfo {
+    fn new(meta: SessionMeta, current_id: &str) -> Self {
+        SessionInfo {
+            current: meta.id == current_id,
+            id: meta.id,
+            user_id: meta.user_id,
+            login_at: meta.login_at,
+            last_seen: meta.last_seen,
+            ip: meta.ip,
+            user_agent: meta.user_agent,
+        }
+    }
+}
+
+/// 没有指定用户的时候使用当前登录的用户
+/// 在有角色之前只能管理自己的session，指定其他用户时返回403
+fn user_id_or_current(ctx: &Context<'_>, user_id: Option<i32>) -> async_graphql::Result<i32> {
+    let current = ctx_data::<CurrentUser>(ctx)?.id;
+    match user_id {
+        Some(id) if id != current => {
+            Err(Error::new(ErrorKind::Forbidden, "只能管理自己的session").into())
+        }
+        _ => Ok(current),
+    }
+}
+
+#[derive(Default)]
+pub struct SessionQuery;
+
+#[Object]
+impl SessionQuery {
+    /// 列出当前用户全部已登录的session
+    #[graphql(guard = "HttpOnlyGuard.and(LoginGuard)")]
+    async fn sessions(
+        &self,
+        ctx: &Context<'_>,
+        user_id: Option<i32>,
+    ) -> async_graphql::Result<Vec<SessionInfo>> {
+        let user_id = user_id_or_current(ctx, user_id)?;
+        let current_id = ctx_data::<SessionHandle>(ctx)?.read().await.id().to_owned();
+        let store = ctx_data::<RotatingSessionLayer>(ctx)?.store().clone();
+        let sessions = tokio::task::spawn_blocking(move || store.list_user_sessions(user_id))
+            .await
+            .map_err(Error::from)?
+            .map_err(Error::from)?;
+        Ok(sessions
+            .into_iter()
+            .map(|meta| SessionInfo::new(meta, &current_id))
+            .collect())
+    }
+}
+
+#[derive(Default)]
+pub struct SessionMutation;
+
+#[Object]
+impl SessionMutation {
+    /// 撤销一个session，返回session是否存在
+    #[graphql(guard = "HttpOnlyGuard.and(LoginGuard)")]
+    async fn revoke_session(
+        &self,
+        ctx: &Context<'_>,
+        user_id: Option<i32>,
+        session_id: String,
+    ) -> async_graphql::Result<bool> {
+        let user_id = user_id_or_current(ctx, user_id)?;
+        let revoked = ctx_data::<RotatingSessionLayer>(ctx)?
+            .store()
+            .revoke_session(user_id, &session_id)
+            .map_err(Error::from)?;
+
+        let mut session = ctx_data::<SessionHandle>(ctx)?.write().await;
+        if session.id() == session_id {
+            // 当前请求结束时session会被重新保存，需要主动销毁
+            auth::logout(&mut session);
+        }
+        Ok(revoked)
+    }
+
+    /// 撤销当前用户的全部session，返回撤销的数量
+    #[graphql(guard = "HttpOnlyGuard.and(LoginGuard)")]
+    async fn revoke_all_sessions(
+        &self,
+        ctx: &Context<'_>,
+        user_id: Option<i32>,
+    ) -> async_graphql::Result<usize> {
+        let user_id = user_id_or_current(ctx, user_id)?;
+        let count = ctx_data::<RotatingSessionLayer>(ctx)?
+            .store()
+            .revoke_user_sessions(user_id)
+            .map_err(Error::from)?;
+
+        if ctx_data::<CurrentUser>(ctx)?.id == user_id {
+            auth::logout(&mut *ctx_data::<SessionHandle>(ctx)?.write().await);
+        }
+        Ok(count)
+    }
+}
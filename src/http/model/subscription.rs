@@ -0,0 +1,159 @@
+use std::time::Duration;
+
+use async_graphql::{Context, Subscription};
+use futures::{Stream, StreamExt};
+use sysinfo::{NetworksExt, System, SystemExt};
+use tokio::sync::broadcast::error::RecvError;
+
+use crate::configure::get_config;
+use crate::http::error::{ctx_data, Error, ErrorKind};
+use crate::http::model::auth::{AdminGuard, LoginGuard};
+use crate::http::model::log::{LogEntry, LogLevel};
+use crate::http::model::system_info::{
+    CpuInfo, DiskInfo, LimitedRefreshSystem, MemorySnapshot, NetworkInfo, RefreshKey,
+};
+use crate::log::broadcast;
+use crate::log::query::LogFilter;
+
+pub struct Subscription;
+
+/// 将客户端请求的推送间隔限制在`system_info_refresh_limit`以上
+/// 没有指定间隔的时候直接使用刷新限制作为间隔
+fn clamp_interval(interval: Option<u64>) -> Duration {
+    let limit = get_config().http.system_info_refresh_limit;
+    interval
+        .map(Duration::from_millis)
+        .map_or(limit, |interval| interval.max(limit))
+}
+
+fn refresh_networks(system: &mut System) {
+    system.refresh_networks_list();
+    system.refresh_networks();
+}
+
+fn refresh_disks(system: &mut System) {
+    system.refresh_disks_list();
+    system.refresh_disks();
+}
+
+#[Subscription]
+impl Subscription {
+    /// 定时推送全局cpu信息(综合全部cpu)
+    #[graphql(guard = "LoginGuard")]
+    async fn cpu(
+        &self,
+        ctx: &Context<'_>,
+        #[graphql(desc = "推送间隔(毫秒), 不会小于服务端的刷新限制")] interval: Option<u64>,
+    ) -> async_graphql::Result<impl Stream<Item = CpuInfo>> {
+        Ok(ctx_data::<LimitedRefreshSystem>(ctx)?.interval_stream(
+            RefreshKey::Cpu,
+            clamp_interval(interval),
+            System::refresh_cpu,
+            |system| system.global_cpu_info().into(),
+        ))
+    }
+
+    /// 定时推送全部cpu的信息
+    #[graphql(guard = "LoginGuard")]
+    async fn cpus(
+        &self,
+        ctx: &Context<'_>,
+        #[graphql(desc = "推送间隔(毫秒), 不会小于服务端的刷新限制")] interval: Option<u64>,
+    ) -> async_graphql::Result<impl Stream<Item = Vec<CpuInfo>>> {
+        Ok(ctx_data::<LimitedRefreshSystem>(ctx)?.interval_stream(
+            RefreshKey::Cpu,
+            clamp_interval(interval),
+            System::refresh_cpu,
+            |system| system.cpus().iter().map(Into::into).collect(),
+        ))
+    }
+
+    /// 定时推送内存信息
+    #[graphql(guard = "LoginGuard")]
+    async fn memory(
+        &self,
+        ctx: &Context<'_>,
+        #[graphql(desc = "推送间隔(毫秒), 不会小于服务端的刷新限制")] interval: Option<u64>,
+    ) -> async_graphql::Result<impl Stream<Item = MemorySnapshot>> {
+        Ok(ctx_data::<LimitedRefreshSystem>(ctx)?.interval_stream(
+            RefreshKey::Memory,
+            clamp_interval(interval),
+            System::refresh_memory,
+            |system| system.into(),
+        ))
+    }
+
+    /// 定时推送全部网络接口的信息
+    #[graphql(guard = "LoginGuard")]
+    async fn networks(
+        &self,
+        ctx: &Context<'_>,
+        #[graphql(desc = "推送间隔(毫秒), 不会小于服务端的刷新限制")] interval: Option<u64>,
+    ) -> async_graphql::Result<impl Stream<Item = Vec<NetworkInfo>>> {
+        Ok(ctx_data::<LimitedRefreshSystem>(ctx)?.interval_stream(
+            RefreshKey::Network,
+            clamp_interval(interval),
+            refresh_networks,
+            |system| system.networks().iter().map(Into::into).collect(),
+        ))
+    }
+
+    /// 定时推送全部磁盘信息
+    #[graphql(guard = "LoginGuard")]
+    async fn disks(
+        &self,
+        ctx: &Context<'_>,
+        #[graphql(desc = "推送间隔(毫秒), 不会小于服务端的刷新限制")] interval: Option<u64>,
+    ) -> async_graphql::Result<impl Stream<Item = Vec<DiskInfo>>> {
+        Ok(ctx_data::<LimitedRefreshSystem>(ctx)?.interval_stream(
+            RefreshKey::Disk,
+            clamp_interval(interval),
+            refresh_disks,
+            |system| system.disks().iter().map(Into::into).collect(),
+        ))
+    }
+
+    /// 实时推送写入日志文件的日志，先推送最近的日志
+    /// 客户端处理不过来时会丢弃部分日志，不会影响服务端写入日志
+    /// 与查询日志文件一样只有管理员可以订阅
+    #[graphql(guard = "AdminGuard")]
+    async fn logs(
+        &self,
+        #[graphql(desc = "只推送这个等级以及更严重的日志")] level: Option<LogLevel>,
+        #[graphql(desc = "模块路径, 同时包含子模块的日志")] target: Option<String>,
+        #[graphql(desc = "先推送的最近日志的最大数量", default = 100)] recent: usize,
+    ) -> async_graphql::Result<impl Stream<Item = LogEntry>> {
+        let subscription = broadcast::subscribe()
+            .ok_or_else(|| Error::new(ErrorKind::Internal, "日志还没有初始化"))?;
+        let filter = LogFilter {
+            level: level.map(Into::into),
+            target,
+            ..Default::default()
+        };
+
+        let mut entries = subscription
+            .recent
+            .iter()
+            .filter_map(|line| filter.matches(line))
+            .collect::<Vec<_>>();
+        entries.drain(..entries.len().saturating_sub(recent));
+        let live = futures::stream::unfold(
+            (subscription.receiver, filter),
+            |(mut rx, filter)| async move {
+                loop {
+                    match rx.recv().await {
+                        Ok(line) => {
+                            if let Some(entry) = filter.matches(&line) {
+                                return Some((entry, (rx, filter)));
+                            }
+                        }
+                        // 落后太多时跳过已经被覆盖的日志
+                        Err(RecvError::Lagged(_)) => continue,
+                        Err(RecvError::Closed) => return None,
+                    }
+                }
+            },
+        );
+        Ok(futures::stream::iter(entries).chain(live).map(Into::into))
+    }
+}
@@ -4,14 +4,17 @@ use std::time::{Duration, Instant};
 use async_graphql::{ComplexObject, Context, Enum, Object, SimpleObject};
 use crossbeam_utils::atomic::AtomicCell;
 use fnv::FnvHashMap;
+use futures::Stream;
 use smol_str::SmolStr;
 use sysinfo::{
     Component, ComponentExt, Cpu, CpuExt, Disk, DiskExt, NetworkData, NetworkExt, NetworksExt,
     System, SystemExt,
 };
 use tokio::sync::{RwLock, RwLockReadGuard};
+use tokio::time::MissedTickBehavior;
 
-use crate::configure::get_config;
+use crate::configure::{get_config, on_config_change};
+use crate::http::error::ctx_data;
 
 /// 生成一些简单的impl块
 /// 主要是可以直接用`system.xx()`调用的不需要手动映射类型的简单方法
@@ -22,7 +25,7 @@ macro_rules! simple_system_info_object {
             $(
                 $(#[$attr])*
                 async fn $name(&self, ctx: &Context<'_>) -> async_graphql::Result<$ret> {
-                    let system = ctx.data::<LimitedRefreshSystem>()?.system().await;
+                    let system = ctx_data::<LimitedRefreshSystem>(ctx)?.system().await;
                     Ok(system.$name())
                 }
             )*
@@ -34,7 +37,7 @@ macro_rules! simple_system_info_object {
             $(
                 $(#[$attr])*
                 async fn $name(&self, ctx: &Context<'_>) -> async_graphql::Result<$ret> {
-                    Ok(ctx.data::<LimitedRefreshSystem>()?
+                    Ok(ctx_data::<LimitedRefreshSystem>(ctx)?
                         .maybe_refresh_nonblocking($refresh_key, System::$refresh, System::$name)
                         .await)
                 }
@@ -55,6 +58,19 @@ pub struct SystemInfo {
 #[derive(Default)]
 pub struct MemoryInfo;
 
+/// 某一时刻的内存快照，字段含义与`MemoryInfo`一致
+/// 用于订阅推送，因为推送的数据需要在刷新时一次性读取出来
+#[derive(SimpleObject)]
+pub struct MemorySnapshot {
+    total_memory: u64,
+    free_memory: u64,
+    available_memory: u64,
+    used_memory: u64,
+    total_swap: u64,
+    free_swap: u64,
+    used_swap: u64,
+}
+
 #[derive(Default)]
 pub struct SystemInfoInner;
 
@@ -194,8 +210,7 @@ simple_system_info_object! {
 impl SystemInfo {
     /// 全局cpu信息(综合全部cpu)
     async fn cpu(&self, ctx: &Context<'_>) -> async_graphql::Result<CpuInfo> {
-        Ok(ctx
-            .data::<LimitedRefreshSystem>()?
+        Ok(ctx_data::<LimitedRefreshSystem>(ctx)?
             .maybe_refresh_nonblocking(RefreshKey::Cpu, System::refresh_cpu, |system| {
                 system.global_cpu_info().into()
             })
@@ -204,8 +219,7 @@ impl SystemInfo {
 
     /// 全部cpu的信息
     async fn cpus(&self, ctx: &Context<'_>) -> async_graphql::Result<Vec<CpuInfo>> {
-        Ok(ctx
-            .data::<LimitedRefreshSystem>()?
+        Ok(ctx_data::<LimitedRefreshSystem>(ctx)?
             .maybe_refresh_nonblocking(RefreshKey::Cpu, System::refresh_cpu, |system| {
                 system.cpus().iter().map(Into::into).collect()
             })
@@ -214,8 +228,7 @@ impl SystemInfo {
 
     /// 全部网络接口的信息
     async fn networks(&self, ctx: &Context<'_>) -> async_graphql::Result<Vec<NetworkInfo>> {
-        Ok(ctx
-            .data::<LimitedRefreshSystem>()?
+        Ok(ctx_data::<LimitedRefreshSystem>(ctx)?
             .maybe_refresh_nonblocking(
                 RefreshKey::Network,
                 |system| {
@@ -233,8 +246,7 @@ impl SystemInfo {
         ctx: &Context<'_>,
         #[graphql(desc = "网络接口名字")] interface_name: SmolStr,
     ) -> async_graphql::Result<Option<NetworkInfo>> {
-        Ok(ctx
-            .data::<LimitedRefreshSystem>()?
+        Ok(ctx_data::<LimitedRefreshSystem>(ctx)?
             .maybe_refresh_nonblocking(
                 RefreshKey::Network,
                 |system| {
@@ -256,8 +268,7 @@ impl SystemInfo {
     /// **Linux**: 虚拟linux系统(例如docker, wsl等)不公开这些信息，所以在这些系统上组件信息可能会丢失或者错误
     /// **Windows**: 需要管理员权限
     async fn components(&self, ctx: &Context<'_>) -> async_graphql::Result<Vec<ComponentInfo>> {
-        Ok(ctx
-            .data::<LimitedRefreshSystem>()?
+        Ok(ctx_data::<LimitedRefreshSystem>(ctx)?
             .maybe_refresh_nonblocking(
                 RefreshKey::Component,
                 |system| {
@@ -271,8 +282,7 @@ impl SystemInfo {
 
     /// 全部磁盘信息
     async fn disks(&self, ctx: &Context<'_>) -> async_graphql::Result<Vec<DiskInfo>> {
-        Ok(ctx
-            .data::<LimitedRefreshSystem>()?
+        Ok(ctx_data::<LimitedRefreshSystem>(ctx)?
             .maybe_refresh_nonblocking(
                 RefreshKey::Disk,
                 |system| {
@@ -291,8 +301,7 @@ impl SystemInfo {
         ctx: &Context<'_>,
         #[graphql(desc = "磁盘名字")] disk_name: SmolStr,
     ) -> async_graphql::Result<Option<DiskInfo>> {
-        Ok(ctx
-            .data::<LimitedRefreshSystem>()?
+        Ok(ctx_data::<LimitedRefreshSystem>(ctx)?
             .maybe_refresh_nonblocking(
                 RefreshKey::Disk,
                 |system| {
@@ -316,8 +325,7 @@ impl SystemInfo {
         ctx: &Context<'_>,
         #[graphql(desc = "磁盘挂载点")] mount_point: SmolStr,
     ) -> async_graphql::Result<Option<DiskInfo>> {
-        Ok(ctx
-            .data::<LimitedRefreshSystem>()?
+        Ok(ctx_data::<LimitedRefreshSystem>(ctx)?
             .maybe_refresh_nonblocking(
                 RefreshKey::Disk,
                 |system| {
@@ -336,6 +344,20 @@ impl SystemInfo {
     }
 }
 
+impl<'a> From<&'a System> for MemorySnapshot {
+    fn from(system: &'a System) -> Self {
+        MemorySnapshot {
+            total_memory: system.total_memory(),
+            free_memory: system.free_memory(),
+            available_memory: system.available_memory(),
+            used_memory: system.used_memory(),
+            total_swap: system.total_swap(),
+            free_swap: system.free_swap(),
+            used_swap: system.used_swap(),
+        }
+    }
+}
+
 impl<'a> From<&'a Cpu> for CpuInfo {
     fn from(cpu: &'a Cpu) -> Self {
         CpuInfo {
@@ -414,10 +436,13 @@ pub enum RefreshKey {
 }
 
 /// 可限制刷新频率的`sysinfo::System`
+/// clone出来的实例共享同一个`System`和刷新时间
+#[derive(Clone)]
 pub struct LimitedRefreshSystem {
     system: Arc<RwLock<System>>,
-    last_refresh: FnvHashMap<RefreshKey, AtomicCell<Instant>>,
-    limit: Duration,
+    last_refresh: Arc<FnvHashMap<RefreshKey, AtomicCell<Instant>>>,
+    /// 跟随配置`system_info_refresh_limit`更新
+    limit: Arc<AtomicCell<Duration>>,
 }
 
 /// 创建包含全部类型的hashmap，这个map必须包含全部的`RefreshKey`
@@ -433,11 +458,22 @@ fn new_last_refresh_map() -> FnvHashMap<RefreshKey, AtomicCell<Instant>> {
 }
 
 impl LimitedRefreshSystem {
+    /// 需要在tokio运行时中调用
     pub fn new() -> Self {
+        let limit = Arc::new(AtomicCell::new(get_config().http.system_info_refresh_limit));
+        // 只持有弱引用，全部实例被drop之后取消订阅
+        let weak = Arc::downgrade(&limit);
+        on_config_change(move |config| match weak.upgrade() {
+            Some(limit) => {
+                limit.store(config.http.system_info_refresh_limit);
+                true
+            }
+            None => false,
+        });
         LimitedRefreshSystem {
             system: Arc::new(RwLock::new(System::new_all())),
-            last_refresh: new_last_refresh_map(),
-            limit: get_config().http.system_info_refresh_limit,
+            last_refresh: Arc::new(new_last_refresh_map()),
+            limit,
         }
     }
 
@@ -458,7 +494,7 @@ impl LimitedRefreshSystem {
         key: RefreshKey,
         f: impl FnOnce(&mut System),
     ) -> RwLockReadGuard<'_, System> {
-        if self.safe_get_last_refresh_unchecked(key).load().elapsed() > self.limit {
+        if self.safe_get_last_refresh_unchecked(key).load().elapsed() > self.limit.load() {
             let mut system = self.system.write().await;
             f(&mut system);
             self.safe_get_last_refresh_unchecked(key)
@@ -472,7 +508,7 @@ impl LimitedRefreshSystem {
     /// `maybe_refresh`的异步非阻塞版本
     ///
     /// `read_fn`: 由于`spawn_blocking`的`'static`生命周期限制，所以读取数据也要一并在执行刷新的线程中执行
-    async fn maybe_refresh_nonblocking<R>(
+    pub(super) async fn maybe_refresh_nonblocking<R>(
         &self,
         key: RefreshKey,
         refresh_fn: impl FnOnce(&mut System) + Send + 'static,
@@ -482,7 +518,7 @@ impl LimitedRefreshSystem {
         R: Send + 'static,
     {
         // 如果距离上次刷新的时间大于限制时间
-        if self.safe_get_last_refresh_unchecked(key).load().elapsed() > self.limit {
+        if self.safe_get_last_refresh_unchecked(key).load().elapsed() > self.limit.load() {
             let system = self.system.clone();
             // 在tokio的阻塞专用线程池中执行刷新和读取数据的闭包
             let ret = tokio::task::spawn_blocking(move || {
@@ -507,6 +543,34 @@ impl LimitedRefreshSystem {
         }
     }
 
+    /// 每隔`period`刷新并读取一次数据的流，第一次读取会立即进行
+    /// 间隔不会小于刷新限制，调用方需要自己处理好
+    pub(super) fn interval_stream<R>(
+        &self,
+        key: RefreshKey,
+        period: Duration,
+        refresh_fn: fn(&mut System),
+        read_fn: fn(&System) -> R,
+    ) -> impl Stream<Item = R>
+    where
+        R: Send + 'static,
+    {
+        let mut interval = tokio::time::interval(period);
+        // 刷新耗时超过间隔的时候直接跳过错过的tick，避免连续推送
+        interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
+
+        futures::stream::unfold(
+            (self.clone(), interval),
+            move |(system, mut interval)| async move {
+                interval.tick().await;
+                let ret = system
+                    .maybe_refresh_nonblocking(key, refresh_fn, read_fn)
+                    .await;
+                Some((ret, (system, interval)))
+            },
+        )
+    }
+
     // SAFETY: 全部RefreshKey都应该已经在`new_last_refresh_map`时插入
     #[inline]
     fn safe_get_last_refresh_unchecked(&self, key: RefreshKey) -> &AtomicCell<Instant> {
@@ -518,7 +582,7 @@ impl LimitedRefreshSystem {
 // 证明`System::refresh_xxx`比较耗时的操作，所以改成使用`maybe_refresh_nonblocking`
 #[tokio::test]
 async fn test_nonblocking_refresh() -> anyhow::Result<()> {
-    crate::configure::init_configure()?;
+    let _config = crate::configure::init_test_configure()?;
     let system = LimitedRefreshSystem::new();
 
     let now = Instant::now();
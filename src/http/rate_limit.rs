@@ -0,0 +1,368 @@
+use std::hash::Hash;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use axum::http::Request;
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use axum::Extension;
+use chrono::Utc;
+use fnv::FnvHashMap;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+use crate::configure::{get_config, RateLimitConfig};
+use crate::http::client_info::ClientIp;
+use crate::http::error::{AnyResult, Error};
+use crate::http::rocksdb_session_store::RocksdbStore;
+
+/// 令牌桶的数量超过这个值时清理已经装满的桶，装满的桶和新建的桶没有区别
+const PRUNE_THRESHOLD: usize = 4096;
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn tokens_at(&self, now: Instant, rate: f64, burst: f64) -> f64 {
+        (self.tokens + now.duration_since(self.updated).as_secs_f64() * rate).min(burst)
+    }
+}
+
+struct BucketMap<K> {
+    buckets: FnvHashMap<K, Bucket>,
+    prune_at: usize,
+}
+
+/// 按key分别计算的令牌桶
+/// 每秒补充`rate`个令牌，最多`burst`个
+struct Buckets<K>(Mutex<BucketMap<K>>);
+
+impl<K: Hash + Eq> Buckets<K> {
+    fn new() -> Self {
+        Buckets(Mutex::new(BucketMap {
+            buckets: FnvHashMap::default(),
+            prune_at: PRUNE_THRESHOLD,
+        }))
+    }
+
+    /// 取出一个令牌，没有令牌时返回需要等待的时间
+    fn take(&self, key: K, rate: f64, burst: u32) -> Result<(), Duration> {
+        let now = Instant::now();
+        let burst = f64::from(burst);
+        let mut map = self.0.lock();
+        if map.buckets.len() >= map.prune_at {
+            map.buckets
+                .retain(|_, bucket| bucket.tokens_at(now, rate, burst) < burst);
+            // 大部分桶都没有装满的时候避免每次都遍历
+            map.prune_at = (map.buckets.len() * 2).max(PRUNE_THRESHOLD);
+        }
+
+        let bucket = map.buckets.entry(key).or_insert(Bucket {
+            tokens: burst,
+            updated: now,
+        });
+        let tokens = bucket.tokens_at(now, rate, burst);
+        bucket.updated = now;
+        if tokens >= 1.0 {
+            bucket.tokens = tokens - 1.0;
+            Ok(())
+        } else {
+            bucket.tokens = tokens;
+            Err(Duration::from_secs_f64((1.0 - tokens) / rate))
+        }
+    }
+}
+
+/// 一个用户名和客户端ip的组合或者一个客户端ip的登录失败记录，时间都是毫秒时间戳
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq, Eq)]
+pub struct LoginFailures {
+    /// 连续失败的次数，登录成功后清零
+    pub count: u32,
+    pub last_failure: i64,
+    pub locked_until: i64,
+}
+
+impl LoginFailures {
+    /// 记录一次失败，返回这次失败导致的锁定时间
+    fn record(&mut self, now: i64, config: &RateLimitConfig) -> Option<Duration> {
+        // 超过锁定时间上限没有再失败的时候重新计数
+        if now - self.last_failure > config.lockout_max_duration.as_millis() as i64 {
+            self.count = 0;
+        }
+        self.count += 1;
+        self.last_failure = now;
+        let lockout = lockout_duration(self.count, config)?;
+        self.locked_until = now + lockout.as_millis() as i64;
+        Some(lockout)
+    }
+}
+
+/// 第`count`次连续失败后的锁定时间，从`lockout_threshold`次开始每次翻倍
+fn lockout_duration(count: u32, config: &RateLimitConfig) -> Option<Duration> {
+    let exponent = count.checked_sub(config.lockout_threshold)?;
+    let lockout = config
+        .lockout_duration
+        .checked_mul(2u32.checked_pow(exponent).unwrap_or(u32::MAX))
+        .unwrap_or(Duration::MAX);
+    Some(lockout.min(config.lockout_max_duration))
+}
+
+/// 登录失败记录在数据库中的key
+/// 按组合计算可以避免在同一个ip上通过登录其他账号清除对某个用户名的失败记录
+/// 不单独按用户名计算，否则任何人都可以通过不断失败锁定这个用户名
+fn lockout_keys(ip: IpAddr, username: &str) -> [String; 2] {
+    [format!("user:{}@{}", username, ip), format!("ip:{}", ip)]
+}
+
+struct Inner {
+    requests: Buckets<IpAddr>,
+    login_ips: Buckets<IpAddr>,
+    /// 按用户名和客户端ip的组合计算，只按用户名计算时任何人都可以在检查密码之前耗尽管理员的令牌
+    login_users: Buckets<(String, IpAddr)>,
+    store: RocksdbStore,
+    /// 登录失败记录的读取和写入之间不能被其他请求插入，否则会丢失失败次数
+    failures: Mutex<()>,
+}
+
+/// 请求速率限制和登录失败锁定
+/// 配置在每次检查时读取，修改后立即生效
+#[derive(Clone)]
+pub struct RateLimiter(Arc<Inner>);
+
+impl RateLimiter {
+    pub fn new(store: RocksdbStore) -> Self {
+        RateLimiter(Arc::new(Inner {
+            requests: Buckets::new(),
+            login_ips: Buckets::new(),
+            login_users: Buckets::new(),
+            store,
+            failures: Mutex::new(()),
+        }))
+    }
+
+    /// 检查登录的速率以及用户名和客户端ip的组合、客户端ip是否被锁定
+    pub fn check_login(&self, ip: IpAddr, username: &str) -> AnyResult<()> {
+        let config = get_config();
+        let limit = &config.http.rate_limit;
+        if !limit.enabled {
+            return Ok(());
+        }
+
+        let rate = limit.login_per_minute / 60.0;
+        self.0
+            .login_ips
+            .take(ip, rate, limit.login_burst)
+            .and_then(|_| {
+                self.0
+                    .login_users
+                    .take((username.to_owned(), ip), rate, limit.login_burst)
+            })
+            .map_err(|retry_after| Error::too_many_requests("登录过于频繁", retry_after))?;
+
+        let now = Utc::now().timestamp_millis();
+        for key in lockout_keys(ip, username) {
+            if let Some(failures) = self.0.store.login_failures(&key)? {
+                if failures.locked_until > now {
+                    return Err(Error::too_many_requests(
+                        "登录失败次数过多，请稍后再试",
+                        Duration::from_millis((failures.locked_until - now) as u64),
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// 记录一次登录失败，达到次数后锁定用户名和客户端ip的组合以及客户端ip
+    /// 读写数据库时需要持有锁，所以在阻塞线程中执行
+    pub async fn login_failed(&self, ip: IpAddr, username: &str) -> anyhow::Result<()> {
+        let limiter = self.clone();
+        let username = username.to_owned();
+        tokio::task::spawn_blocking(move || limiter.record_failure(ip, &username)).await?
+    }
+
+    fn record_failure(&self, ip: IpAddr, username: &str) -> anyhow::Result<()> {
+        let config = get_config();
+        let limit = &config.http.rate_limit;
+        if !limit.enabled {
+            return Ok(());
+        }
+
+        let _guard = self.0.failures.lock();
+        let now = Utc::now().timestamp_millis();
+        for key in lockout_keys(ip, username) {
+            let mut failures = self.0.store.login_failures(&key)?.unwrap_or_default();
+            if let Some(lockout) = failures.record(now, limit) {
+                warn!("{}连续登录失败{}次，锁定{:?}", key, failures.count, lockout);
+            }
+            self.0.store.put_login_failures(&key, &failures)?;
+        }
+        Ok(())
+    }
+
+    /// 登录成功后清除失败记录
+    pub async fn login_succeeded(&self, ip: IpAddr, username: &str) -> anyhow::Result<()> {
+        let limiter = self.clone();
+        let username = username.to_owned();
+        tokio::task::spawn_blocking(move || -> anyhow::Result<()> {
+            let _guard = limiter.0.failures.lock();
+            for key in lockout_keys(ip, &username) {
+                limiter.0.store.delete_login_failures(&key)?;
+            }
+            Ok(())
+        })
+        .await?
+    }
+}
+
+/// 按客户端ip限制全部请求的速率
+pub async fn limit_requests<B>(
+    Extension(limiter): Extension<RateLimiter>,
+    req: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let config = get_config();
+    let limit = &config.http.rate_limit;
+    if let (true, Some(ClientIp(ip))) = (limit.enabled, req.extensions().get::<ClientIp>()) {
+        if let Err(retry_after) =
+            limiter
+                .0
+                .requests
+                .take(*ip, limit.requests_per_second, limit.burst)
+        {
+            return Error::too_many_requests("请求过于频繁", retry_after).into_response();
+        }
+    }
+    drop(config);
+    next.run(req).await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::IpAddr;
+    use std::time::Duration;
+
+    use crate::configure::{get_config, init_test_configure};
+    use crate::http::rate_limit::{
+        lockout_duration, lockout_keys, Buckets, LoginFailures, RateLimiter,
+    };
+    use crate::http::rocksdb_session_store::RocksdbStore;
+
+    #[test]
+    fn test_buckets() {
+        let buckets = Buckets::new();
+        assert!(buckets.take(1, 1.0, 2).is_ok());
+        assert!(buckets.take(1, 1.0, 2).is_ok());
+        let retry_after = buckets.take(1, 1.0, 2).unwrap_err();
+        assert!(retry_after > Duration::ZERO && retry_after <= Duration::from_secs(1));
+        // 不同的key互不影响
+        assert!(buckets.take(2, 1.0, 2).is_ok());
+    }
+
+    #[test]
+    fn test_lockout() -> anyhow::Result<()> {
+        let _config = init_test_configure()?;
+        let mut config = get_config().http.rate_limit.clone();
+        config.lockout_threshold = 3;
+        config.lockout_duration = Duration::from_secs(60);
+        config.lockout_max_duration = Duration::from_secs(300);
+
+        assert_eq!(lockout_duration(2, &config), None);
+        assert_eq!(lockout_duration(3, &config), Some(Duration::from_secs(60)));
+        assert_eq!(lockout_duration(4, &config), Some(Duration::from_secs(120)));
+        assert_eq!(lockout_duration(6, &config), Some(Duration::from_secs(300)));
+        assert_eq!(
+            lockout_duration(100, &config),
+            Some(Duration::from_secs(300))
+        );
+
+        let mut failures = LoginFailures::default();
+        let now = 1_000_000_000;
+        assert_eq!(failures.record(now, &config), None);
+        assert_eq!(failures.record(now, &config), None);
+        assert_eq!(failures.record(now, &config), Some(Duration::from_secs(60)));
+        assert_eq!(failures.locked_until, now + 60_000);
+        // 很久之后再失败重新计数
+        assert_eq!(failures.record(now + 301_000, &config), None);
+        assert_eq!(failures.count, 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_concurrent_failures() -> anyhow::Result<()> {
+        let _config = init_test_configure()?;
+        let dir = tempfile::tempdir()?;
+        let limiter = RateLimiter::new(RocksdbStore::open(dir.path())?);
+        let ip = IpAddr::from([127, 0, 0, 1]);
+
+        std::thread::scope(|scope| {
+            for _ in 0..16 {
+                scope.spawn(|| {
+                    for _ in 0..64 {
+                        limiter.record_failure(ip, "admin").unwrap();
+                    }
+                });
+            }
+        });
+        // 并发的失败不会互相覆盖
+        for key in lockout_keys(ip, "admin") {
+            assert_eq!(
+                limiter.0.store.login_failures(&key)?.unwrap().count,
+                16 * 64
+            );
+        }
+        // 其他ip不受影响
+        let other = IpAddr::from([127, 0, 0, 2]);
+        assert!(limiter.check_login(other, "admin").is_ok());
+        assert!(limiter.check_login(ip, "admin").is_err());
+        assert!(limiter.check_login(ip, "cat").is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_no_username_lockout() -> anyhow::Result<()> {
+        let _config = init_test_configure()?;
+        let dir = tempfile::tempdir()?;
+        let limiter = RateLimiter::new(RocksdbStore::open(dir.path())?);
+        let limit = get_config().http.rate_limit.clone();
+
+        // 来自很多ip的失败只会锁定这些ip，不会让管理员在其他ip上也无法登录
+        for i in 0..16u8 {
+            let ip = IpAddr::from([10, 0, 0, i]);
+            for _ in 0..limit.lockout_threshold {
+                limiter.record_failure(ip, "admin")?;
+            }
+            assert!(limiter.check_login(ip, "admin").is_err());
+        }
+        let other = IpAddr::from([10, 1, 0, 0]);
+        assert!(limiter.check_login(other, "admin").is_ok());
+
+        // 登录成功后清除
+        let ip = IpAddr::from([10, 0, 0, 0]);
+        limiter.login_succeeded(ip, "admin").await?;
+        assert!(limiter.check_login(ip, "admin").is_ok());
+        Ok(())
+    }
+
+    #[test]
+    fn test_login_rate() -> anyhow::Result<()> {
+        let _config = init_test_configure()?;
+        let dir = tempfile::tempdir()?;
+        let limiter = RateLimiter::new(RocksdbStore::open(dir.path())?);
+        let ip = IpAddr::from([127, 0, 0, 1]);
+
+        let burst = get_config().http.rate_limit.login_burst;
+        for _ in 0..burst {
+            assert!(limiter.check_login(ip, "admin").is_ok());
+        }
+        assert!(limiter.check_login(ip, "admin").is_err());
+        // 其他ip上的同一个用户名不受影响
+        let other = IpAddr::from([127, 0, 0, 2]);
+        assert!(limiter.check_login(other, "admin").is_ok());
+        Ok(())
+    }
+}
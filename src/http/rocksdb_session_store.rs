@@ -1,13 +1,21 @@
+use std::path::Path;
 use std::sync::Arc;
 
 use async_trait::async_trait;
 use axum_sessions::async_session::{Session, SessionStore};
 use bincode::config::Configuration;
-use bincode::serde::{decode_from_slice, encode_into_slice};
+use bincode::serde::{decode_from_slice, encode_to_vec};
+use chrono::{DateTime, TimeZone, Utc};
 use rocksdb::{
-    DBCompressionType, DBWithThreadMode, IteratorMode, Options, SingleThreaded, WriteBatch, DB,
+    ColumnFamily, DBCompressionType, DBWithThreadMode, Direction, IteratorMode, Options,
+    SingleThreaded, WriteBatch, DB,
 };
-use smallvec::SmallVec;
+use tokio_graceful_shutdown::SubsystemHandle;
+use tracing::{debug, error};
+
+use crate::configure::get_config;
+use crate::http::auth::{SESSION_CLIENT_IP, SESSION_LOGIN_AT, SESSION_USER_AGENT, SESSION_USER_ID};
+use crate::http::rate_limit::LoginFailures;
 
 const BINCODE_CONFIG: Configuration = bincode::config::standard()
     .with_little_endian()
@@ -15,11 +23,41 @@ const BINCODE_CONFIG: Configuration = bincode::config::standard()
     .write_fixed_array_length()
     .with_no_limit();
 
+/// 用户session索引的column family
+/// key为`用户id(大端序) + session id`，value为最后一次访问的时间戳
+const USER_SESSIONS_CF: &str = "user_sessions";
+/// 登录失败记录的column family，key见`rate_limit::lockout_keys`，value为`LoginFailures`
+const LOGIN_FAILURES_CF: &str = "login_failures";
+/// 已经撤销或者销毁的session id，value为可以清理这条记录的时间戳(大端序)
+/// 撤销时还在处理中的请求结束时会重新保存session，有记录的id不会再被保存
+const REVOKED_SESSIONS_CF: &str = "revoked_sessions";
+
 #[derive(Debug, Clone)]
 pub struct RocksdbStore(Arc<DBWithThreadMode<SingleThreaded>>);
 
+/// 已登录session的元数据
+#[derive(Debug, Clone)]
+pub struct SessionMeta {
+    pub id: String,
+    pub user_id: i32,
+    pub login_at: Option<DateTime<Utc>>,
+    pub last_seen: Option<DateTime<Utc>>,
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
+}
+
+fn index_key(user_id: i32, session_id: &str) -> Vec<u8> {
+    let mut key = user_id.to_be_bytes().to_vec();
+    key.extend_from_slice(session_id.as_bytes());
+    key
+}
+
 impl RocksdbStore {
     pub fn new() -> anyhow::Result<Self> {
+        Self::open(&get_config().storage.sessions())
+    }
+
+    pub(crate) fn open(path: &Path) -> anyhow::Result<Self> {
         let mut opts = Options::default();
         opts.create_if_missing(true);
         opts.create_missing_column_families(true);
@@ -27,40 +65,344 @@ impl RocksdbStore {
         opts.set_bottommost_compression_type(DBCompressionType::Zstd);
         opts.set_level_compaction_dynamic_level_bytes(true);
 
-        let db = DB::open(&opts, "sessions")?;
+        let db = DB::open_cf(
+            &opts,
+            path,
+            [USER_SESSIONS_CF, LOGIN_FAILURES_CF, REVOKED_SESSIONS_CF],
+        )?;
 
         Ok(RocksdbStore(Arc::new(db)))
     }
+
+    #[inline]
+    fn index_cf(&self) -> &ColumnFamily {
+        self.0
+            .cf_handle(USER_SESSIONS_CF)
+            .expect("打开数据库时已经创建了user_sessions")
+    }
+
+    #[inline]
+    fn login_failures_cf(&self) -> &ColumnFamily {
+        self.0
+            .cf_handle(LOGIN_FAILURES_CF)
+            .expect("打开数据库时已经创建了login_failures")
+    }
+
+    #[inline]
+    fn revoked_cf(&self) -> &ColumnFamily {
+        self.0
+            .cf_handle(REVOKED_SESSIONS_CF)
+            .expect("打开数据库时已经创建了revoked_sessions")
+    }
+
+    fn get_session(&self, id: &str) -> anyhow::Result<Option<Session>> {
+        Ok(match self.0.get(id.as_bytes())? {
+            Some(val) => Some(decode_from_slice(&val, BINCODE_CONFIG)?.0),
+            None => None,
+        })
+    }
+
+    /// 按id读取未过期的session
+    pub fn active_session(&self, id: &str) -> anyhow::Result<Option<Session>> {
+        Ok(self.get_session(id)?.filter(|session| !is_expired(session)))
+    }
+
+    /// 在batch中删除session和它的索引
+    fn delete_session(&self, batch: &mut WriteBatch, session: &Session) {
+        batch.delete(session.id().as_bytes());
+        if let Some(user_id) = session.get::<i32>(SESSION_USER_ID) {
+            batch.delete_cf(self.index_cf(), index_key(user_id, session.id()));
+        }
+    }
+
+    /// 在batch中记录session已经被撤销，session本身需要另外删除
+    /// 超过从登录开始的最长有效期之后，即使重新保存也已经过期，记录就可以清理了
+    fn mark_revoked(&self, batch: &mut WriteBatch, session_id: &str) {
+        let until = Utc::now().timestamp() + get_config().http.session_ttl.as_secs() as i64;
+        batch.put_cf(
+            self.revoked_cf(),
+            session_id.as_bytes(),
+            until.to_be_bytes(),
+        );
+    }
+
+    fn is_revoked(&self, session_id: &str) -> anyhow::Result<bool> {
+        Ok(self
+            .0
+            .get_cf(self.revoked_cf(), session_id.as_bytes())?
+            .is_some())
+    }
+
+    /// 列出某个用户全部未过期的session
+    pub fn list_user_sessions(&self, user_id: i32) -> anyhow::Result<Vec<SessionMeta>> {
+        let prefix = user_id.to_be_bytes();
+        let mut sessions = Vec::new();
+        for res in self.0.iterator_cf(
+            self.index_cf(),
+            IteratorMode::From(&prefix, Direction::Forward),
+        ) {
+            let (key, val) = res?;
+            if !key.starts_with(&prefix) {
+                break;
+            }
+            let id = String::from_utf8_lossy(&key[prefix.len()..]).into_owned();
+            let session = match self.get_session(&id)? {
+                Some(session) if !is_expired(&session) => session,
+                // 过期的session等待gc清理
+                _ => continue,
+            };
+            let timestamp = |ts: i64| Utc.timestamp_opt(ts, 0).single();
+            sessions.push(SessionMeta {
+                id,
+                user_id,
+                login_at: session.get::<i64>(SESSION_LOGIN_AT).and_then(timestamp),
+                last_seen: val
+                    .as_ref()
+                    .try_into()
+                    .ok()
+                    .and_then(|bytes| timestamp(i64::from_be_bytes(bytes))),
+                ip: session.get(SESSION_CLIENT_IP),
+                user_agent: session.get(SESSION_USER_AGENT),
+            });
+        }
+        Ok(sessions)
+    }
+
+    /// 撤销某个用户的一个session，返回session是否存在
+    pub fn revoke_session(&self, user_id: i32, session_id: &str) -> anyhow::Result<bool> {
+        let session = match self.get_session(session_id)? {
+            Some(session) if session.get::<i32>(SESSION_USER_ID) == Some(user_id) => session,
+            _ => return Ok(false),
+        };
+        let mut batch = WriteBatch::default();
+        self.delete_session(&mut batch, &session);
+        self.mark_revoked(&mut batch, session_id);
+        self.0.write(batch)?;
+        Ok(true)
+    }
+
+    /// 撤销某个用户的全部session，返回撤销的数量
+    pub fn revoke_user_sessions(&self, user_id: i32) -> anyhow::Result<usize> {
+        let ids = self
+            .list_user_sessions(user_id)?
+            .into_iter()
+            .map(|meta| meta.id)
+            .collect::<Vec<_>>();
+        let mut batch = WriteBatch::default();
+        for id in &ids {
+            batch.delete(id.as_bytes());
+            batch.delete_cf(self.index_cf(), index_key(user_id, id));
+            self.mark_revoked(&mut batch, id);
+        }
+        self.0.write(batch)?;
+        Ok(ids.len())
+    }
+
+    pub fn login_failures(&self, key: &str) -> anyhow::Result<Option<LoginFailures>> {
+        Ok(
+            match self.0.get_cf(self.login_failures_cf(), key.as_bytes())? {
+                Some(val) => Some(decode_from_slice(&val, BINCODE_CONFIG)?.0),
+                None => None,
+            },
+        )
+    }
+
+    pub fn put_login_failures(&self, key: &str, failures: &LoginFailures) -> anyhow::Result<()> {
+        self.0.put_cf(
+            self.login_failures_cf(),
+            key.as_bytes(),
+            encode_to_vec(failures, BINCODE_CONFIG)?,
+        )?;
+        Ok(())
+    }
+
+    pub fn delete_login_failures(&self, key: &str) -> anyhow::Result<()> {
+        self.0.delete_cf(self.login_failures_cf(), key.as_bytes())?;
+        Ok(())
+    }
+
+    /// 删除最后一次失败早于`before`(毫秒时间戳)的记录，返回删除的数量
+    fn remove_stale_login_failures(&self, before: i64) -> anyhow::Result<usize> {
+        let mut batch = WriteBatch::default();
+        for res in self
+            .0
+            .iterator_cf(self.login_failures_cf(), IteratorMode::Start)
+        {
+            let (key, val) = res?;
+            match decode_from_slice::<LoginFailures, _>(&val, BINCODE_CONFIG) {
+                Ok((failures, _)) if failures.last_failure >= before => continue,
+                _ => batch.delete_cf(self.login_failures_cf(), key),
+            }
+        }
+        let count = batch.len();
+        self.0.write(batch)?;
+        Ok(count)
+    }
+
+    /// 删除全部已经过期的session，返回删除的数量
+    /// 需要遍历整个数据库，所以是阻塞的
+    fn remove_expired(&self) -> anyhow::Result<usize> {
+        let mut batch = WriteBatch::default();
+        let mut count = 0;
+        for res in self.0.iterator(IteratorMode::Start) {
+            let (key, val) = res?;
+            match decode_from_slice::<Session, _>(&val, BINCODE_CONFIG) {
+                Ok((session, _)) if is_expired(&session) => {
+                    self.delete_session(&mut batch, &session)
+                }
+                Ok(_) => continue,
+                // 无法解析的session也一并删除
+                Err(_) => batch.delete(key),
+            }
+            count += 1;
+        }
+        self.0.write(batch)?;
+
+        // 清理指向已经不存在的session的索引和已经不需要的撤销记录
+        let mut batch = WriteBatch::default();
+        for res in self.0.iterator_cf(self.index_cf(), IteratorMode::Start) {
+            let (key, _) = res?;
+            if key.len() < 4 || self.0.get(&key[4..])?.is_none() {
+                batch.delete_cf(self.index_cf(), key);
+            }
+        }
+        let now = Utc::now().timestamp();
+        for res in self.0.iterator_cf(self.revoked_cf(), IteratorMode::Start) {
+            let (key, val) = res?;
+            match val.as_ref().try_into() {
+                Ok(bytes) if i64::from_be_bytes(bytes) >= now => continue,
+                _ => batch.delete_cf(self.revoked_cf(), key),
+            }
+        }
+        self.0.write(batch)?;
+        Ok(count)
+    }
+}
+
+/// 打开数据库失败是不是因为数据库已经被其他进程(正在运行的面板)打开
+pub fn is_locked(err: &anyhow::Error) -> bool {
+    // RocksDB只在错误信息里说明原因，例如`IO error: While lock file: sessions/LOCK: Resource temporarily unavailable`
+    err.downcast_ref::<rocksdb::Error>().is_some_and(|err| {
+        err.kind() == rocksdb::ErrorKind::IOError && err.as_ref().contains("LOCK")
+    })
+}
+
+/// session是否已经过期
+/// 除了闲置超时(由`SessionLayer`每次请求时刷新的expiry)以外，还检查从登录开始计算的最长有效期
+fn is_expired(session: &Session) -> bool {
+    if session.is_expired() {
+        return true;
+    }
+    match session
+        .get::<i64>(SESSION_LOGIN_AT)
+        .and_then(|ts| Utc.timestamp_opt(ts, 0).single())
+    {
+        Some(login_at) => {
+            Utc::now()
+                .signed_duration_since(login_at)
+                .to_std()
+                .unwrap_or_default()
+                > get_config().http.session_ttl
+        }
+        None => false,
+    }
+}
+
+/// 定期清理过期session的子系统
+pub async fn session_gc(store: RocksdbStore, handle: SubsystemHandle) -> anyhow::Result<()> {
+    let mut interval = tokio::time::interval(get_config().http.session_gc_interval);
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {}
+            _ = handle.on_shutdown_requested() => break,
+        }
+        let store = store.clone();
+        // 超过锁定时间上限没有再失败的记录已经不会再导致锁定
+        let before = Utc::now().timestamp_millis()
+            - get_config()
+                .http
+                .rate_limit
+                .lockout_max_duration
+                .as_millis() as i64;
+        let (sessions, failures) = tokio::task::spawn_blocking(move || {
+            (
+                store.remove_expired(),
+                store.remove_stale_login_failures(before),
+            )
+        })
+        .await?;
+        match sessions {
+            Ok(0) => {}
+            Ok(count) => debug!("清理了{}个过期session", count),
+            Err(err) => error!("清理过期session失败: {}", err),
+        }
+        match failures {
+            Ok(0) => {}
+            Ok(count) => debug!("清理了{}条过期的登录失败记录", count),
+            Err(err) => error!("清理登录失败记录失败: {}", err),
+        }
+    }
+    Ok(())
 }
 
 #[async_trait]
 impl SessionStore for RocksdbStore {
     async fn load_session(&self, cookie_value: String) -> anyhow::Result<Option<Session>> {
         let id = Session::id_from_cookie_value(&cookie_value)?;
-        Ok(Some(
-            decode_from_slice(
-                &if let Some(val) = self.0.get(id.as_bytes())? {
-                    val
-                } else {
-                    return Ok(None);
-                },
-                BINCODE_CONFIG,
-            )?
-            .0,
-        ))
+        let session: Session = decode_from_slice(
+            &if let Some(val) = self.0.get(id.as_bytes())? {
+                val
+            } else {
+                return Ok(None);
+            },
+            BINCODE_CONFIG,
+        )?
+        .0;
+
+        if is_expired(&session) {
+            let mut batch = WriteBatch::default();
+            self.delete_session(&mut batch, &session);
+            self.0.write(batch)?;
+            return Ok(None);
+        }
+        Ok(Some(session))
     }
 
     async fn store_session(&self, session: Session) -> anyhow::Result<Option<String>> {
-        let mut data = SmallVec::<[u8; 256]>::new();
-        encode_into_slice(&session, &mut data, BINCODE_CONFIG)?;
-        self.0.put(session.id().as_bytes(), &data)?;
+        let mut batch = WriteBatch::default();
+        batch.put(
+            session.id().as_bytes(),
+            encode_to_vec(&session, BINCODE_CONFIG)?,
+        );
+        // 已登录的session同时更新用户索引和最后访问时间
+        if let Some(user_id) = session.get::<i32>(SESSION_USER_ID) {
+            batch.put_cf(
+                self.index_cf(),
+                index_key(user_id, session.id()),
+                Utc::now().timestamp().to_be_bytes(),
+            );
+        }
+        self.0.write(batch)?;
+
+        // 在写入之后再检查，撤销发生在检查和写入之间时也能删除重新写入的session
+        if self.is_revoked(session.id())? {
+            let mut batch = WriteBatch::default();
+            self.delete_session(&mut batch, &session);
+            self.0.write(batch)?;
+            debug!("不再保存已经撤销的session");
+            return Ok(None);
+        }
 
         session.reset_data_changed();
         Ok(session.into_cookie_value())
     }
 
     async fn destroy_session(&self, session: Session) -> anyhow::Result<()> {
-        self.0.delete(session.id().as_bytes()).map_err(Into::into)
+        let mut batch = WriteBatch::default();
+        self.delete_session(&mut batch, &session);
+        // 退出登录时同一个session的其他请求可能还在处理中
+        self.mark_revoked(&mut batch, session.id());
+        self.0.write(batch).map_err(Into::into)
     }
 
     async fn clear_store(&self) -> anyhow::Result<()> {
@@ -69,10 +411,140 @@ impl SessionStore for RocksdbStore {
             .iterator(IteratorMode::Start)
             .try_for_each::<_, anyhow::Result<()>>(|res| {
                 let (key, _) = res?;
+                self.mark_revoked(&mut batch, &String::from_utf8_lossy(&key));
                 batch.delete(key);
                 Ok(())
             })?;
+        self.0
+            .iterator_cf(self.index_cf(), IteratorMode::Start)
+            .try_for_each::<_, anyhow::Result<()>>(|res| {
+                let (key, _) = res?;
+                batch.delete_cf(self.index_cf(), key);
+                Ok(())
+            })?;
         self.0.write(batch)?;
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use axum_sessions::async_session::{Session, SessionStore};
+    use chrono::{Duration, Utc};
+
+    use crate::http::auth::{SESSION_CLIENT_IP, SESSION_LOGIN_AT, SESSION_USER_ID};
+    use crate::http::rocksdb_session_store::{index_key, is_expired, is_locked, RocksdbStore};
+
+    #[test]
+    fn test_is_expired() -> anyhow::Result<()> {
+        let _config = crate::configure::init_test_configure()?;
+
+        let mut session = Session::new();
+        session.insert(SESSION_LOGIN_AT, Utc::now().timestamp())?;
+        assert!(!is_expired(&session));
+
+        // 超过了从登录开始的最长有效期
+        session.insert(
+            SESSION_LOGIN_AT,
+            (Utc::now() - Duration::days(30)).timestamp(),
+        )?;
+        assert!(is_expired(&session));
+
+        // 闲置超时
+        let mut session = Session::new();
+        session.set_expiry(Utc::now() - Duration::seconds(1));
+        assert!(is_expired(&session));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_user_sessions() -> anyhow::Result<()> {
+        let _config = crate::configure::init_test_configure()?;
+        let dir = tempfile::tempdir()?;
+        let store = RocksdbStore::open(dir.path())?;
+
+        let login = |user_id: i32| -> anyhow::Result<Session> {
+            let mut session = Session::new();
+            session.insert(SESSION_USER_ID, user_id)?;
+            session.insert(SESSION_LOGIN_AT, Utc::now().timestamp())?;
+            session.insert(SESSION_CLIENT_IP, "127.0.0.1")?;
+            Ok(session)
+        };
+        let a = login(1)?;
+        let b = login(1)?;
+        let other = login(2)?;
+        for session in [&a, &b, &other, &Session::new()] {
+            store.store_session(session.clone()).await?;
+        }
+
+        // 保存时写入索引，未登录的session不在任何用户的列表里
+        let mut ids = store
+            .list_user_sessions(1)?
+            .into_iter()
+            .map(|meta| {
+                assert_eq!(meta.ip.as_deref(), Some("127.0.0.1"));
+                assert!(meta.last_seen.is_some());
+                meta.id
+            })
+            .collect::<Vec<_>>();
+        ids.sort();
+        let mut expected = vec![a.id().to_owned(), b.id().to_owned()];
+        expected.sort();
+        assert_eq!(ids, expected);
+
+        // 只能撤销属于这个用户的session
+        assert!(!store.revoke_session(2, a.id())?);
+        assert!(store.revoke_session(1, a.id())?);
+        assert!(!store.revoke_session(1, a.id())?);
+        assert!(store.0.get(a.id().as_bytes())?.is_none());
+        assert_eq!(store.list_user_sessions(1)?.len(), 1);
+
+        // 撤销时还在处理中的请求结束后不能把session重新保存回来
+        assert!(store.store_session(a.clone()).await?.is_none());
+        assert!(store.0.get(a.id().as_bytes())?.is_none());
+        assert_eq!(store.list_user_sessions(1)?.len(), 1);
+
+        assert_eq!(store.revoke_user_sessions(1)?, 1);
+        assert!(store.store_session(b.clone()).await?.is_none());
+        assert!(store.list_user_sessions(1)?.is_empty());
+        assert!(store
+            .0
+            .get_cf(store.index_cf(), index_key(1, b.id()))?
+            .is_none());
+        assert_eq!(store.list_user_sessions(2)?.len(), 1);
+
+        // gc删除过期的session和它的索引，以及指向不存在的session的索引
+        let mut expired = login(2)?;
+        expired.set_expiry(Utc::now() - Duration::seconds(1));
+        store.store_session(expired.clone()).await?;
+        store
+            .0
+            .put_cf(store.index_cf(), index_key(3, "missing"), [])?;
+        assert_eq!(store.remove_expired()?, 1);
+        assert!(store
+            .0
+            .get_cf(store.index_cf(), index_key(2, expired.id()))?
+            .is_none());
+        assert!(store
+            .0
+            .get_cf(store.index_cf(), index_key(3, "missing"))?
+            .is_none());
+        let remaining = store.list_user_sessions(2)?;
+        assert_eq!(remaining.len(), 1);
+        assert_eq!(remaining[0].id, other.id());
+        Ok(())
+    }
+
+    #[test]
+    fn test_is_locked() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let _store = RocksdbStore::open(dir.path())?;
+        assert!(is_locked(&RocksdbStore::open(dir.path()).unwrap_err()));
+
+        // 其他原因的错误不能当作面板正在运行
+        let file = dir.path().join("file");
+        std::fs::write(&file, "")?;
+        assert!(!is_locked(&RocksdbStore::open(&file).unwrap_err()));
+        Ok(())
+    }
+}
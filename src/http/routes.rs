@@ -1,5 +1,98 @@
-use crate::http::error::AnyResult;
+use async_graphql::http::GraphiQLSource;
+use async_graphql_axum::{GraphQLRequest, GraphQLResponse};
+use axum::extract::rejection::JsonRejection;
+use axum::http::{StatusCode, Uri};
+use axum::response::{Html, IntoResponse};
+use axum::{Extension, Json};
+use axum_sessions::extractors::WritableSession;
+use axum_sessions::SessionHandle;
+use serde::{Deserialize, Serialize};
+
+use crate::http::auth::{self, CurrentUser};
+use crate::http::client_info::ClientInfo;
+use crate::http::error::{AnyResult, Error, ErrorKind};
+use crate::http::model::AppSchema;
+use crate::http::rate_limit::RateLimiter;
+use crate::http::session_secret::RotatingSessionLayer;
+use crate::http::trace;
 
 pub async fn hello_world() -> AnyResult<&'static str> {
     Ok("Hello, World!")
 }
+
+pub async fn graphql(
+    Extension(schema): Extension<AppSchema>,
+    Extension(session): Extension<SessionHandle>,
+    Extension(session_layer): Extension<RotatingSessionLayer>,
+    Extension(limiter): Extension<RateLimiter>,
+    client: ClientInfo,
+    user: Option<CurrentUser>,
+    req: GraphQLRequest,
+) -> GraphQLResponse {
+    let req = req.into_inner();
+    trace::record_graphql_operation(&req);
+    let mut req = req
+        .data(session)
+        .data(session_layer)
+        .data(limiter)
+        .data(client);
+    if let Some(user) = user {
+        req = req.data(user);
+    }
+    schema.execute(req).await.into()
+}
+
+/// GraphiQL调试页面
+pub async fn graphiql() -> impl IntoResponse {
+    Html(
+        GraphiQLSource::build()
+            .endpoint("/graphql")
+            .subscription_endpoint("/ws")
+            .finish(),
+    )
+}
+
+#[derive(Deserialize)]
+pub struct LoginForm {
+    username: String,
+    password: String,
+}
+
+#[derive(Serialize)]
+pub struct LoginResponse {
+    id: i32,
+    username: String,
+}
+
+pub async fn login(
+    Extension(limiter): Extension<RateLimiter>,
+    Extension(session_layer): Extension<RotatingSessionLayer>,
+    mut session: WritableSession,
+    client: ClientInfo,
+    form: Result<Json<LoginForm>, JsonRejection>,
+) -> AnyResult<Json<LoginResponse>> {
+    let Json(form) = form.map_err(|err| Error::new(ErrorKind::BadRequest, err.to_string()))?;
+    let user = auth::login(
+        &limiter,
+        session_layer.store(),
+        &mut session,
+        &client,
+        &form.username,
+        form.password,
+    )
+    .await?
+    .ok_or_else(|| Error::new(ErrorKind::Unauthorized, "用户名或密码错误"))?;
+    Ok(Json(LoginResponse {
+        id: user.id,
+        username: user.username,
+    }))
+}
+
+pub async fn not_found(uri: Uri) -> Error {
+    Error::new(ErrorKind::NotFound, format!("{}不存在", uri.path()))
+}
+
+pub async fn logout(mut session: WritableSession) -> AnyResult<StatusCode> {
+    auth::logout(&mut session);
+    Ok(StatusCode::NO_CONTENT)
+}
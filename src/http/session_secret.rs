@@ -0,0 +1,369 @@
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use anyhow::{bail, Context as _};
+use arc_swap::ArcSwap;
+use axum::http::header::COOKIE;
+use axum::http::{HeaderMap, HeaderValue, Request};
+use axum::response::Response;
+use axum_sessions::async_session::hmac::{Hmac, Mac, NewMac};
+use axum_sessions::async_session::sha2::Sha256;
+use axum_sessions::async_session::{base64, SessionStore};
+use axum_sessions::{PersistencePolicy, SessionLayer};
+use futures::future::BoxFuture;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use tower::{Layer, Service};
+use tracing::info;
+
+use crate::configure::get_config;
+use crate::http::rocksdb_session_store::RocksdbStore;
+
+/// 轮换后仍然接受的旧密钥数量
+const MAX_PREVIOUS_SECRETS: usize = 3;
+const SECRET_LEN: usize = 64;
+/// `cookie::Key`使用密钥的前32字节作为签名密钥
+const SIGNING_KEY_LEN: usize = 32;
+/// base64编码后的HMAC-SHA256长度
+const BASE64_DIGEST_LEN: usize = 44;
+
+pub const SESSION_COOKIE_NAME: &str = "axum.sid";
+
+/// session cookie的签名密钥
+/// `current`用于签名和校验，`previous`只用于校验，校验通过的cookie会被重新用`current`签名
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Secrets {
+    current: Vec<u8>,
+    previous: Vec<Vec<u8>>,
+}
+
+impl Secrets {
+    fn generate() -> Self {
+        Secrets {
+            current: new_secret(),
+            previous: Vec::new(),
+        }
+    }
+
+    /// 读取密钥文件，不存在则生成一个新的并保存
+    pub fn load_or_create() -> anyhow::Result<Self> {
+        Self::load_or_create_from(&get_config().storage.session_secret())
+    }
+
+    fn load_or_create_from(path: &Path) -> anyhow::Result<Self> {
+        if !path.exists() {
+            let secrets = Self::generate();
+            secrets.save_to(path)?;
+            info!("已生成新的session密钥: {}", path.display());
+            return Ok(secrets);
+        }
+
+        let data = std::fs::read(path).with_context(|| "读取session密钥失败")?;
+        let secrets: Secrets =
+            bincode::serde::decode_from_slice(&data, bincode::config::standard())
+                .with_context(|| "session密钥文件已损坏")?
+                .0;
+        if secrets.current.len() != SECRET_LEN {
+            bail!("session密钥长度错误");
+        }
+        Ok(secrets)
+    }
+
+    /// 先写入临时文件再重命名，避免写入一半的时候崩溃导致密钥丢失
+    pub fn save(&self) -> anyhow::Result<()> {
+        self.save_to(&get_config().storage.session_secret())
+    }
+
+    fn save_to(&self, path: &Path) -> anyhow::Result<()> {
+        let tmp = path.with_extension("tmp");
+        let mut opts = OpenOptions::new();
+        opts.create(true).write(true).truncate(true);
+        // 密钥只允许当前用户读写
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut opts, 0o600);
+
+        let mut file = opts.open(&tmp).with_context(|| "写入session密钥失败")?;
+        file.write_all(&bincode::serde::encode_to_vec(
+            self,
+            bincode::config::standard(),
+        )?)?;
+        file.sync_all()?;
+        std::fs::rename(&tmp, path)?;
+        // 同步所在的目录，否则崩溃后重命名可能丢失，重启时会生成新的密钥
+        if let Some(dir) = path.parent() {
+            File::open(dir)?.sync_all()?;
+        }
+        Ok(())
+    }
+
+    /// 生成新的密钥
+    /// `keep_previous`为true时旧密钥仍然可以用于校验，已经登录的用户不会受影响
+    pub fn rotate(&mut self, keep_previous: bool) {
+        let old = std::mem::replace(&mut self.current, new_secret());
+        if keep_previous {
+            self.previous.insert(0, old);
+            self.previous.truncate(MAX_PREVIOUS_SECRETS);
+        } else {
+            self.previous.clear();
+        }
+    }
+
+    /// 把由旧密钥签名的session cookie重新用当前密钥签名
+    /// 这样`SessionLayer`只需要知道当前密钥
+    fn resign_cookies(&self, headers: &mut HeaderMap) {
+        if self.previous.is_empty() {
+            return;
+        }
+
+        let mut changed = false;
+        let cookies = headers
+            .get_all(COOKIE)
+            .iter()
+            .filter_map(|header| header.to_str().ok())
+            .map(|header| {
+                header
+                    .split(';')
+                    .map(|pair| {
+                        let pair = pair.trim();
+                        match pair.split_once('=') {
+                            Some((SESSION_COOKIE_NAME, value))
+                                if verify(&self.current, value).is_none() =>
+                            {
+                                match self.previous.iter().find_map(|key| verify(key, value)) {
+                                    Some(value) => {
+                                        changed = true;
+                                        format!(
+                                            "{}={}",
+                                            SESSION_COOKIE_NAME,
+                                            sign(&self.current, value)
+                                        )
+                                    }
+                                    None => pair.to_owned(),
+                                }
+                            }
+                            _ => pair.to_owned(),
+                        }
+                    })
+                    .collect::<Vec<_>>()
+                    .join("; ")
+            })
+            .collect::<Vec<_>>();
+
+        if changed {
+            headers.remove(COOKIE);
+            for cookie in cookies {
+                if let Ok(value) = HeaderValue::from_str(&cookie) {
+                    headers.append(COOKIE, value);
+                }
+            }
+        }
+    }
+}
+
+fn new_secret() -> Vec<u8> {
+    rand::thread_rng().gen::<[u8; SECRET_LEN]>().to_vec()
+}
+
+/// 与`axum_sessions`的签名方式保持一致: base64(HMAC-SHA256(value)) + value
+fn sign(secret: &[u8], value: &str) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(&secret[..SIGNING_KEY_LEN]).expect("good key");
+    mac.update(value.as_bytes());
+    let mut signed = base64::encode(mac.finalize().into_bytes());
+    signed.push_str(value);
+    signed
+}
+
+fn verify<'a>(secret: &[u8], signed: &'a str) -> Option<&'a str> {
+    if signed.len() < BASE64_DIGEST_LEN || !signed.is_char_boundary(BASE64_DIGEST_LEN) {
+        return None;
+    }
+    let (digest, value) = signed.split_at(BASE64_DIGEST_LEN);
+    let digest = base64::decode(digest).ok()?;
+    let mut mac = Hmac::<Sha256>::new_from_slice(&secret[..SIGNING_KEY_LEN]).expect("good key");
+    mac.update(value.as_bytes());
+    mac.verify(&digest).ok().map(|_| value)
+}
+
+struct State {
+    secrets: Secrets,
+    layer: SessionLayer<RocksdbStore>,
+}
+
+impl State {
+    fn new(store: RocksdbStore, secrets: Secrets) -> Self {
+        let layer = SessionLayer::new(store, &secrets.current)
+            .with_cookie_name(SESSION_COOKIE_NAME)
+            // 每次请求都会刷新expiry，所以这里的ttl实际上是闲置超时
+            .with_session_ttl(Some(get_config().http.session_idle_timeout))
+            // 不保存未登录的访客session
+            .with_persistence_policy(PersistencePolicy::ExistingOnly);
+        State { secrets, layer }
+    }
+}
+
+/// 可以在运行时轮换签名密钥的`SessionLayer`
+#[derive(Clone)]
+pub struct RotatingSessionLayer {
+    store: RocksdbStore,
+    state: Arc<ArcSwap<State>>,
+    /// 启动时的密钥文件，修改`storage`需要重启
+    path: PathBuf,
+}
+
+impl RotatingSessionLayer {
+    pub fn new(store: RocksdbStore) -> anyhow::Result<Self> {
+        Self::with_secret_file(store, get_config().storage.session_secret())
+    }
+
+    fn with_secret_file(store: RocksdbStore, path: PathBuf) -> anyhow::Result<Self> {
+        let secrets = Secrets::load_or_create_from(&path)?;
+        Ok(RotatingSessionLayer {
+            state: Arc::new(ArcSwap::from_pointee(State::new(store.clone(), secrets))),
+            store,
+            path,
+        })
+    }
+
+    pub fn store(&self) -> &RocksdbStore {
+        &self.store
+    }
+
+    /// 轮换密钥并持久化
+    /// `logout_all`为true时同时丢弃旧密钥并清空全部session，所有用户都需要重新登录
+    pub async fn rotate(&self, logout_all: bool) -> anyhow::Result<()> {
+        // 面板运行期间命令行可能轮换过密钥，以文件为准，否则会覆盖掉命令行添加的密钥
+        // 正在使用的密钥还没有在文件里的话作为旧密钥保留，已经登录的用户不受影响
+        let mut secrets = Secrets::load_or_create_from(&self.path)?;
+        let running = self.state.load().secrets.current.clone();
+        if secrets.current != running && !secrets.previous.contains(&running) {
+            secrets.previous.insert(0, running);
+        }
+        secrets.rotate(!logout_all);
+        secrets.save_to(&self.path)?;
+        if logout_all {
+            self.store.clear_store().await?;
+        }
+        self.state
+            .store(Arc::new(State::new(self.store.clone(), secrets)));
+        info!("session密钥已轮换, logout_all: {}", logout_all);
+        Ok(())
+    }
+}
+
+impl<Inner> Layer<Inner> for RotatingSessionLayer {
+    type Service = RotatingSession<Inner>;
+
+    fn layer(&self, inner: Inner) -> Self::Service {
+        RotatingSession {
+            inner,
+            state: self.state.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct RotatingSession<Inner> {
+    inner: Inner,
+    state: Arc<ArcSwap<State>>,
+}
+
+impl<Inner, ReqBody, ResBody> Service<Request<ReqBody>> for RotatingSession<Inner>
+where
+    Inner: Service<Request<ReqBody>, Response = Response<ResBody>> + Clone + Send + 'static,
+    ResBody: Send + 'static,
+    ReqBody: Send + 'static,
+    Inner::Future: Send + 'static,
+{
+    type Response = Inner::Response;
+    type Error = Inner::Error;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, mut req: Request<ReqBody>) -> Self::Future {
+        let state = self.state.load();
+        state.secrets.resign_cookies(req.headers_mut());
+        // 使用已经ready的inner，留下一个clone供下次使用
+        let inner = self.inner.clone();
+        let inner = std::mem::replace(&mut self.inner, inner);
+        state.layer.layer(inner).call(req)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::header::COOKIE;
+    use axum::http::{HeaderMap, HeaderValue};
+
+    use crate::configure::init_test_configure;
+    use crate::http::rocksdb_session_store::RocksdbStore;
+    use crate::http::session_secret::{
+        sign, verify, RotatingSessionLayer, Secrets, SESSION_COOKIE_NAME,
+    };
+
+    #[test]
+    fn test_resign_cookies() {
+        let mut secrets = Secrets::generate();
+        let old_cookie = sign(&secrets.current, "session-id");
+        assert_eq!(verify(&secrets.current, &old_cookie), Some("session-id"));
+
+        secrets.rotate(true);
+        assert!(verify(&secrets.current, &old_cookie).is_none());
+
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            COOKIE,
+            HeaderValue::from_str(&format!("a=b; {}={}", SESSION_COOKIE_NAME, old_cookie)).unwrap(),
+        );
+        secrets.resign_cookies(&mut headers);
+        let header = headers.get(COOKIE).unwrap().to_str().unwrap();
+        let (other, session) = header.split_once("; ").unwrap();
+        assert_eq!(other, "a=b");
+        let value = session
+            .strip_prefix(&format!("{}=", SESSION_COOKIE_NAME))
+            .unwrap();
+        assert_eq!(verify(&secrets.current, value), Some("session-id"));
+
+        // 丢弃旧密钥后旧cookie不再被接受
+        secrets.rotate(false);
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            COOKIE,
+            HeaderValue::from_str(&format!("{}={}", SESSION_COOKIE_NAME, old_cookie)).unwrap(),
+        );
+        secrets.resign_cookies(&mut headers);
+        assert_eq!(
+            headers.get(COOKIE).unwrap().to_str().unwrap(),
+            format!("{}={}", SESSION_COOKIE_NAME, old_cookie)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_rotate_after_cli() -> anyhow::Result<()> {
+        let _config = init_test_configure()?;
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("session_secret");
+        let store = RocksdbStore::open(&dir.path().join("sessions"))?;
+        let layer = RotatingSessionLayer::with_secret_file(store, path.clone())?;
+        let running = layer.state.load().secrets.current.clone();
+
+        // 面板运行期间通过命令行轮换，面板仍然使用原来的密钥
+        let mut secrets = Secrets::load_or_create_from(&path)?;
+        secrets.rotate(true);
+        secrets.save_to(&path)?;
+        let cli = secrets.current.clone();
+
+        // 之后通过webui轮换时不会丢失命令行添加的密钥，也不会丢失面板正在使用的密钥
+        layer.rotate(false).await?;
+        let saved = Secrets::load_or_create_from(&path)?;
+        assert_eq!(saved.current, layer.state.load().secrets.current);
+        assert!(saved.previous.contains(&cli));
+        assert!(saved.previous.contains(&running));
+        Ok(())
+    }
+}
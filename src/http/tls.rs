@@ -0,0 +1,202 @@
+use std::fs::OpenOptions;
+use std::io::{BufReader, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use anyhow::{bail, Context};
+use arc_swap::ArcSwap;
+use axum::extract::Host;
+use axum::http::Uri;
+use axum::response::Redirect;
+use axum::Router;
+use rcgen::{Certificate, CertificateParams, DnType, SanType};
+use tokio_graceful_shutdown::SubsystemHandle;
+use tokio_rustls::rustls::{self, ServerConfig};
+use tracing::{error, info, warn};
+
+use crate::configure::{Config, FileWatcher};
+
+/// 读取证书和私钥，两者都不存在并且开启了`self_signed`时先生成自签名证书
+pub fn load_server_config(config: &Config) -> anyhow::Result<ServerConfig> {
+    let (cert, key) = cert_paths(config);
+    if config.http.tls.self_signed && !cert.exists() && !key.exists() {
+        let ips = config
+            .http
+            .tcp_binds()
+            .map(|addr| addr.ip())
+            .collect::<Vec<_>>();
+        generate_self_signed(&cert, &key, &ips)?;
+        warn!(
+            "已生成自签名证书{}，浏览器会提示证书不受信任，建议替换为正式的证书",
+            cert.display()
+        );
+    }
+    read_server_config(&cert, &key)
+}
+
+/// 证书和私钥的完整路径
+pub fn cert_paths(config: &Config) -> (PathBuf, PathBuf) {
+    (
+        config.storage.resolve(&config.http.tls.cert),
+        config.storage.resolve(&config.http.tls.key),
+    )
+}
+
+fn read_server_config(cert: &Path, key: &Path) -> anyhow::Result<ServerConfig> {
+    let certs = rustls_pemfile::certs(&mut BufReader::new(
+        std::fs::File::open(cert).with_context(|| format!("无法读取证书{}", cert.display()))?,
+    ))?;
+    if certs.is_empty() {
+        bail!("{}中没有证书", cert.display());
+    }
+    let key_der = rustls_pemfile::read_all(&mut BufReader::new(
+        std::fs::File::open(key).with_context(|| format!("无法读取私钥{}", key.display()))?,
+    ))?
+    .into_iter()
+    .find_map(|item| match item {
+        rustls_pemfile::Item::RSAKey(key)
+        | rustls_pemfile::Item::PKCS8Key(key)
+        | rustls_pemfile::Item::ECKey(key) => Some(key),
+        _ => None,
+    });
+    let key_der = match key_der {
+        Some(key) => key,
+        None => bail!("{}中没有私钥", key.display()),
+    };
+
+    let mut config = ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(
+            certs.into_iter().map(rustls::Certificate).collect(),
+            rustls::PrivateKey(key_der),
+        )?;
+    config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    Ok(config)
+}
+
+/// 生成包含localhost和监听地址的自签名证书
+fn generate_self_signed(cert: &Path, key: &Path, ips: &[IpAddr]) -> anyhow::Result<()> {
+    let mut params = CertificateParams::default();
+    params
+        .distinguished_name
+        .push(DnType::CommonName, "CatPanel");
+    params.subject_alt_names = vec![
+        SanType::DnsName("localhost".into()),
+        SanType::IpAddress(IpAddr::V4(Ipv4Addr::LOCALHOST)),
+        SanType::IpAddress(IpAddr::V6(Ipv6Addr::LOCALHOST)),
+    ];
+    params.subject_alt_names.extend(
+        ips.iter()
+            .filter(|ip| !ip.is_unspecified() && !ip.is_loopback())
+            .map(|ip| SanType::IpAddress(*ip)),
+    );
+    let generated = Certificate::from_params(params)?;
+
+    for path in [cert, key] {
+        if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+            std::fs::create_dir_all(parent)?;
+        }
+    }
+    std::fs::write(cert, generated.serialize_pem()?)?;
+    let mut opts = OpenOptions::new();
+    opts.create(true).write(true).truncate(true);
+    // 私钥只允许当前用户读写
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut opts, 0o600);
+    opts.open(key)?
+        .write_all(generated.serialize_private_key_pem().as_bytes())?;
+    Ok(())
+}
+
+/// 证书或私钥文件变化时重新加载，新的证书无效时继续使用原来的证书
+/// 已经建立的连接不受影响
+pub async fn watch_certificates(
+    server_config: Arc<ArcSwap<ServerConfig>>,
+    cert: PathBuf,
+    key: PathBuf,
+    handle: SubsystemHandle,
+) -> anyhow::Result<()> {
+    let mut watcher = FileWatcher::new(&[cert.clone(), key.clone()])?;
+    loop {
+        tokio::select! {
+            _ = handle.on_shutdown_requested() => break,
+            _ = watcher.changed() => match read_server_config(&cert, &key) {
+                Ok(config) => {
+                    server_config.store(Arc::new(config));
+                    info!("已重新加载TLS证书");
+                }
+                Err(err) => error!("重新加载TLS证书失败，继续使用原来的证书: {:#}", err),
+            },
+        }
+    }
+    Ok(())
+}
+
+/// 在`bind`上把全部HTTP请求重定向到HTTPS
+pub async fn redirect_to_https(
+    bind: SocketAddr,
+    https_port: u16,
+    handle: SubsystemHandle,
+) -> anyhow::Result<()> {
+    let app = Router::new().fallback(move |Host(host): Host, uri: Uri| async move {
+        Redirect::permanent(&https_url(&host, https_port, &uri))
+    });
+    let server = axum::Server::try_bind(&bind).with_context(|| format!("无法监听{}", bind))?;
+    info!("redirecting http on {} to https", bind);
+    server
+        .serve(app.into_make_service())
+        .with_graceful_shutdown(handle.on_shutdown_requested())
+        .await
+        .map_err(Into::into)
+}
+
+fn https_url(host: &str, port: u16, uri: &Uri) -> String {
+    // 去掉Host中的端口，ipv6地址本身也包含`:`所以需要判断是否在`]`后面
+    let hostname = match host.rfind(':') {
+        Some(i) if !host[i..].contains(']') => &host[..i],
+        _ => host,
+    };
+    let path = uri.path_and_query().map_or("/", |path| path.as_str());
+    if port == 443 {
+        format!("https://{}{}", hostname, path)
+    } else {
+        format!("https://{}:{}{}", hostname, port, path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::IpAddr;
+
+    use axum::http::Uri;
+
+    use crate::http::tls::{generate_self_signed, https_url, read_server_config};
+
+    #[test]
+    fn test_https_url() {
+        let uri = Uri::from_static("/graphql?a=1");
+        assert_eq!(
+            https_url("example.com:80", 8443, &uri),
+            "https://example.com:8443/graphql?a=1"
+        );
+        assert_eq!(
+            https_url("[::1]:80", 443, &uri),
+            "https://[::1]/graphql?a=1"
+        );
+        assert_eq!(
+            https_url("[::1]", 443, &Uri::from_static("/")),
+            "https://[::1]/"
+        );
+    }
+
+    #[test]
+    fn test_self_signed() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let (cert, key) = (dir.path().join("cert.pem"), dir.path().join("key.pem"));
+        generate_self_signed(&cert, &key, &[IpAddr::from([0, 0, 0, 0])])?;
+        read_server_config(&cert, &key)?;
+        Ok(())
+    }
+}
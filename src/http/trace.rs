@@ -0,0 +1,153 @@
+use std::time::Instant;
+
+use async_graphql::parser::types::DocumentOperations;
+use axum::http::Request;
+use axum::middleware::Next;
+use axum::response::Response;
+use tracing::field::Empty;
+use tracing::{info, Instrument, Span};
+
+use crate::http::client_info::ClientIp;
+use crate::http::request_id;
+use crate::log::ACCESS_LOG_TARGET;
+
+/// 每个请求一个span，请求结束时向`access_log`输出一条访问日志
+/// `user`和`graphql_operation`在处理请求的过程中才能知道，由`record_user`和`record_graphql_operation`填入
+pub async fn trace_request<B>(req: Request<B>, next: Next<B>) -> Response {
+    let method = req.method().clone();
+    let path = req.uri().path().to_owned();
+    let span = tracing::info_span!(
+        "request",
+        request_id = request_id::current(),
+        method = %method,
+        path,
+        client_ip = req.extensions().get::<ClientIp>().map(|ip| display(ip.0)),
+        user = Empty,
+        graphql_operation = Empty,
+    );
+
+    let start = Instant::now();
+    let res = next.run(req).instrument(span.clone()).await;
+    span.in_scope(|| {
+        info!(
+            target: ACCESS_LOG_TARGET,
+            status = res.status().as_u16(),
+            latency_ms = start.elapsed().as_secs_f64() * 1000.0,
+            "{} {}",
+            method,
+            path
+        )
+    });
+    res
+}
+
+/// 在当前请求的span中记录登录的用户id
+pub fn record_user(id: i32) {
+    Span::current().record("user", id);
+}
+
+/// 在当前请求的span中记录graphql的操作名
+/// 请求没有指定`operationName`时使用查询中唯一的具名操作，匿名操作不记录
+pub fn record_graphql_operation(req: &async_graphql::Request) {
+    let name = match &req.operation_name {
+        Some(name) => Some(name.clone()),
+        None => match async_graphql::parser::parse_query(&req.query) {
+            Ok(doc) => match doc.operations {
+                DocumentOperations::Multiple(operations) if operations.len() == 1 => {
+                    operations.into_keys().next().map(|name| name.to_string())
+                }
+                _ => None,
+            },
+            Err(_) => None,
+        },
+    };
+    if let Some(name) = name {
+        Span::current().record("graphql_operation", name.as_str());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+    use std::sync::Arc;
+
+    use axum::body::Body;
+    use axum::http::{Request, StatusCode};
+    use axum::middleware;
+    use axum::routing::post;
+    use axum::Router;
+    use parking_lot::Mutex;
+    use tower::ServiceExt;
+    use tracing_subscriber::layer::SubscriberExt;
+    use tracing_subscriber::Layer;
+
+    use crate::http::request_id::{self, REQUEST_ID_HEADER};
+    use crate::http::trace::{record_graphql_operation, record_user, trace_request};
+    use crate::log::ACCESS_LOG_TARGET;
+
+    #[derive(Clone, Default)]
+    struct Captured(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Captured {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_access_log() -> anyhow::Result<()> {
+        let captured = Captured::default();
+        let writer = captured.clone();
+        // 与`log::init`中的访问日志相同的格式
+        let subscriber = tracing_subscriber::registry().with(
+            tracing_subscriber::fmt::layer()
+                .json()
+                .flatten_event(true)
+                .with_current_span(true)
+                .with_span_list(false)
+                .with_writer(move || writer.clone())
+                .with_filter(tracing_subscriber::filter::filter_fn(|metadata| {
+                    metadata.is_span() || metadata.target() == ACCESS_LOG_TARGET
+                })),
+        );
+        let _guard = tracing::subscriber::set_default(subscriber);
+
+        let app = Router::new()
+            .route(
+                "/graphql",
+                post(|| async {
+                    record_user(7);
+                    record_graphql_operation(&async_graphql::Request::new(
+                        "query Me { me { id } }",
+                    ));
+                    StatusCode::ACCEPTED
+                }),
+            )
+            .layer(middleware::from_fn(trace_request))
+            .layer(middleware::from_fn(request_id::set_request_id));
+        let res = app
+            .oneshot(
+                Request::post("/graphql")
+                    .header(REQUEST_ID_HEADER, "test-request")
+                    .body(Body::empty())?,
+            )
+            .await?;
+        assert_eq!(res.status(), StatusCode::ACCEPTED);
+
+        let output = String::from_utf8(captured.0.lock().clone())?;
+        let lines = output.lines().collect::<Vec<_>>();
+        assert_eq!(lines.len(), 1);
+        let entry = serde_json::from_str::<serde_json::Value>(lines[0])?;
+        assert_eq!(entry["status"], 202);
+        assert_eq!(entry["message"], "POST /graphql");
+        assert_eq!(entry["span"]["request_id"], "test-request");
+        assert_eq!(entry["span"]["user"], 7);
+        assert_eq!(entry["span"]["graphql_operation"], "Me");
+        Ok(())
+    }
+}
@@ -1,9 +1,115 @@
+use std::sync::Arc;
+
+use async_graphql::http::ALL_WEBSOCKET_PROTOCOLS;
+use async_graphql::{Data, Executor, Pos, Request, Response};
+use async_graphql_axum::{GraphQLProtocol, GraphQLWebSocket};
 use axum::extract::ws::WebSocket;
 use axum::extract::WebSocketUpgrade;
 use axum::response::IntoResponse;
+use axum::Extension;
+use axum_sessions::SessionHandle;
+use futures::stream::BoxStream;
+use futures::StreamExt;
+
+use crate::database::get_database;
+use crate::http::auth::{CurrentUser, SessionWatch};
+use crate::http::error::{Error, ErrorKind};
+use crate::http::model::AppSchema;
+use crate::http::session_secret::RotatingSessionLayer;
+
+/// graphql订阅，支持`graphql-ws`和`graphql-transport-ws`两种协议
+/// 登录检查在升级之前由`require_login`完成，之后由`SessionExecutor`在每个操作和每次推送时重新检查
+/// 连接上没有`SessionHandle`，登录、退出和管理session的操作会被`HttpOnlyGuard`拒绝
+pub async fn ws_route(
+    Extension(schema): Extension<AppSchema>,
+    Extension(user): Extension<CurrentUser>,
+    Extension(session): Extension<SessionHandle>,
+    Extension(session_layer): Extension<RotatingSessionLayer>,
+    protocol: GraphQLProtocol,
+    ws: WebSocketUpgrade,
+) -> impl IntoResponse {
+    let watch = SessionWatch::new(
+        session_layer.store().clone(),
+        get_database().clone(),
+        session.read().await.id().to_owned(),
+        user.id,
+    );
+    ws.protocols(ALL_WEBSOCKET_PROTOCOLS)
+        .on_upgrade(move |socket| handle(socket, schema, protocol, user, watch))
+}
+
+async fn handle(
+    ws: WebSocket,
+    schema: AppSchema,
+    protocol: GraphQLProtocol,
+    user: CurrentUser,
+    watch: SessionWatch,
+) {
+    let mut data = Data::default();
+    data.insert(user);
+    GraphQLWebSocket::new(ws, SessionExecutor::new(schema, watch), protocol)
+        .with_data(data)
+        .serve()
+        .await
+}
 
-pub async fn ws_route(ws: WebSocketUpgrade) -> impl IntoResponse {
-    ws.on_upgrade(handle)
+/// websocket连接上使用的executor
+/// 连接建立之后`CurrentUser`不会再更新，注销、撤销session或者修改密码之后需要由这里拒绝后续的操作
+/// 执行每个操作之前都会检查session，订阅的每一条推送在发出之前也会检查，失效后返回错误并结束
+#[derive(Clone)]
+pub(crate) struct SessionExecutor {
+    schema: AppSchema,
+    watch: SessionWatch,
 }
 
-async fn handle(mut ws: WebSocket) {}
+impl SessionExecutor {
+    pub(crate) fn new(schema: AppSchema, watch: SessionWatch) -> Self {
+        SessionExecutor { schema, watch }
+    }
+}
+
+fn session_expired() -> Response {
+    let err = async_graphql::Error::from(Error::new(
+        ErrorKind::Unauthorized,
+        "登录已失效，请重新登录",
+    ));
+    Response::from_errors(vec![err.into_server_error(Pos::default())])
+}
+
+#[async_trait::async_trait]
+impl Executor for SessionExecutor {
+    async fn execute(&self, request: Request) -> Response {
+        if !self.watch.check().await {
+            return session_expired();
+        }
+        self.schema.execute(request).await
+    }
+
+    fn execute_stream(
+        &self,
+        request: Request,
+        session_data: Option<Arc<Data>>,
+    ) -> BoxStream<'static, Response> {
+        let schema = self.schema.clone();
+        let watch = self.watch.clone();
+        futures::stream::once(async move {
+            // websocket上的查询和修改也会走这里，必须在执行之前检查
+            if !watch.check().await {
+                return futures::stream::iter([session_expired()]).boxed();
+            }
+            let stream = Executor::execute_stream(&schema, request, session_data);
+            futures::stream::unfold(Some((stream, watch)), |state| async move {
+                let (mut stream, watch) = state?;
+                let resp = stream.next().await?;
+                if watch.check().await {
+                    Some((resp, Some((stream, watch))))
+                } else {
+                    Some((session_expired(), None))
+                }
+            })
+            .boxed()
+        })
+        .flatten()
+        .boxed()
+    }
+}
@@ -0,0 +1,159 @@
+//! 日志文件写入器的吞吐量，使用`cargo +nightly bench --features bench log::bench`运行
+//! `spawn_per_line`为之前每一行日志创建一个task再发送到容量为100的channel的实现
+//! 全部都在tokio的工作线程中写入，只统计真正写入文件的行数，被丢弃的日志会再补写
+
+extern crate test;
+
+use std::io::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use tempfile::TempDir;
+use test::Bencher;
+use tokio::io::AsyncWriteExt;
+use tokio::runtime::Runtime;
+
+use crate::configure::{get_config, init_configure, OverflowPolicy};
+use crate::log::writer::{self, MakeLogFileWriter};
+
+const LINES: u64 = 10_000;
+const LINE: &[u8] = br#"{"timestamp":"2023-01-02T03:04:05.678901Z","level":"INFO","message":"request finished","target":"cat_panel_backend::http","filename":"src/http/mod.rs","line_number":42,"threadName":"tokio-runtime-worker","threadId":"ThreadId(3)"}
+"#;
+
+fn setup() -> anyhow::Result<(Runtime, TempDir)> {
+    init_configure()?;
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()?;
+    Ok((runtime, tempfile::tempdir()?))
+}
+
+/// 已经写入文件和已经丢弃的行数
+#[derive(Debug, Clone, Copy, Default)]
+struct Progress {
+    written: u64,
+    dropped: u64,
+}
+
+/// 在tokio的工作线程中写入，直到放入队列(没有被丢弃)的行数达到`target`，然后等待它们全部写入文件
+/// `sent`为累计写入的行数，包括被丢弃的
+fn write_lines<W, P>(runtime: &Runtime, w: &W, sent: &mut u64, target: u64, progress: P)
+where
+    W: Write + Clone + Send + 'static,
+    P: Fn() -> Progress + Clone + Send + 'static,
+{
+    let mut w = w.clone();
+    let start = *sent;
+    let producer = progress.clone();
+    *sent = runtime
+        .block_on(runtime.spawn(async move {
+            let mut sent = start;
+            // 丢弃是在写入时同步计数的，所以每次补写的行数刚好是还差的行数
+            loop {
+                let queued = sent - producer().dropped;
+                if queued >= target {
+                    break sent;
+                }
+                for _ in queued..target {
+                    w.write_all(LINE).unwrap();
+                }
+                sent += target - queued;
+            }
+        }))
+        .unwrap();
+    while progress().written < target {
+        std::thread::yield_now();
+    }
+}
+
+/// 之前的实现
+#[derive(Clone)]
+struct SpawnPerLine(tokio::sync::mpsc::Sender<Vec<u8>>);
+
+impl Write for SpawnPerLine {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let sender = self.0.clone();
+        let owned_buf = buf.to_vec();
+        tokio::spawn(async move {
+            let _ = sender.send(owned_buf).await;
+        });
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[bench]
+fn bench_spawn_per_line(b: &mut Bencher) -> anyhow::Result<()> {
+    let (runtime, dir) = setup()?;
+    let written = Arc::new(AtomicU64::new(0));
+    let (tx, mut rx) = tokio::sync::mpsc::channel::<Vec<u8>>(100);
+    let consumer_written = written.clone();
+    let path = dir.path().join("spawn_per_line.log");
+    runtime.spawn(async move {
+        let mut file = tokio::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .await
+            .unwrap();
+        while let Some(buf) = rx.recv().await {
+            file.write_all(&buf).await.unwrap();
+            consumer_written.fetch_add(1, Ordering::Relaxed);
+        }
+    });
+
+    let w = SpawnPerLine(tx);
+    let (mut sent, mut target) = (0, 0);
+    b.bytes = LINES * LINE.len() as u64;
+    b.iter(|| {
+        target += LINES;
+        let written = written.clone();
+        write_lines(&runtime, &w, &mut sent, target, move || Progress {
+            written: written.load(Ordering::Relaxed),
+            dropped: 0,
+        });
+    });
+    Ok(())
+}
+
+fn bench_queue(
+    b: &mut Bencher,
+    prefix: &'static str,
+    overflow: OverflowPolicy,
+) -> anyhow::Result<()> {
+    let (runtime, dir) = setup()?;
+    let mut config = get_config().log.clone();
+    config.overflow = overflow;
+    let (opened, _rx) = tokio::sync::mpsc::unbounded_channel();
+    let (w, guard) = MakeLogFileWriter::spawn(dir.path().to_owned(), prefix, opened, &config)?;
+
+    let progress = move || {
+        writer::stats()
+            .into_iter()
+            .filter(|stats| stats.prefix == prefix)
+            .fold(Progress::default(), |progress, stats| Progress {
+                written: progress.written + stats.written_lines,
+                dropped: progress.dropped + stats.dropped_lines,
+            })
+    };
+    let (mut sent, mut target) = (0, 0);
+    b.bytes = LINES * LINE.len() as u64;
+    b.iter(|| {
+        target += LINES;
+        write_lines(&runtime, &w, &mut sent, target, progress);
+    });
+    runtime.block_on(guard.shutdown())
+}
+
+#[bench]
+fn bench_queue_block(b: &mut Bencher) -> anyhow::Result<()> {
+    bench_queue(b, "bench-block-", OverflowPolicy::Block)
+}
+
+#[bench]
+fn bench_queue_drop(b: &mut Bencher) -> anyhow::Result<()> {
+    bench_queue(b, "bench-drop-", OverflowPolicy::Drop)
+}
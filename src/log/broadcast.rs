@@ -0,0 +1,126 @@
+use std::collections::VecDeque;
+use std::sync::Arc;
+
+use once_cell::sync::OnceCell;
+use parking_lot::Mutex;
+use tokio::sync::broadcast::{self, Receiver, Sender};
+use tracing_subscriber::fmt::MakeWriter;
+
+/// 广播channel的容量，订阅者落后超过这个数量时会丢弃旧的日志
+const CHANNEL_CAPACITY: usize = 1024;
+
+static BROADCAST: OnceCell<LogBroadcast> = OnceCell::new();
+
+/// 把写入日志文件的每一行同时广播给实时订阅日志的客户端
+/// 写入不会等待订阅者，订阅者处理不过来时会丢失日志
+#[derive(Clone)]
+pub struct LogBroadcast(Arc<Inner>);
+
+struct Inner {
+    sender: Sender<Arc<str>>,
+    /// 最近的日志，新的订阅者会先收到这些日志
+    recent: Mutex<VecDeque<Arc<str>>>,
+    capacity: usize,
+}
+
+impl LogBroadcast {
+    fn new(capacity: usize) -> Self {
+        LogBroadcast(Arc::new(Inner {
+            sender: broadcast::channel(CHANNEL_CAPACITY).0,
+            recent: Mutex::new(VecDeque::with_capacity(capacity)),
+            capacity,
+        }))
+    }
+
+    fn send(&self, line: Arc<str>) {
+        let mut recent = self.0.recent.lock();
+        if self.0.capacity > 0 {
+            if recent.len() == self.0.capacity {
+                recent.pop_front();
+            }
+            recent.push_back(line.clone());
+        }
+        // 没有订阅者时会返回错误，直接忽略
+        let _ = self.0.sender.send(line);
+    }
+
+    /// 在同一个锁内获取最近的日志和订阅新的日志，两者之间不会丢失或者重复
+    fn subscribe(&self) -> LogSubscription {
+        let recent = self.0.recent.lock();
+        LogSubscription {
+            recent: recent.iter().cloned().collect(),
+            receiver: self.0.sender.subscribe(),
+        }
+    }
+}
+
+/// 每一项为一行json
+pub struct LogSubscription {
+    /// 订阅之前最近的日志
+    pub recent: Vec<Arc<str>>,
+    /// 订阅之后的新日志
+    pub receiver: Receiver<Arc<str>>,
+}
+
+/// 在`init_tracing_subscriber`中创建，`capacity`为保留的最近日志条数
+pub fn init(capacity: usize) -> LogBroadcast {
+    BROADCAST
+        .get_or_init(|| LogBroadcast::new(capacity))
+        .clone()
+}
+
+/// 日志没有初始化时返回`None`
+pub fn subscribe() -> Option<LogSubscription> {
+    BROADCAST.get().map(LogBroadcast::subscribe)
+}
+
+impl std::io::Write for LogBroadcast {
+    /// fmt层每条日志只会写入一次，`buf`是完整的一行
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if let Ok(line) = std::str::from_utf8(buf) {
+            self.send(Arc::from(line));
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<'a> MakeWriter<'a> for LogBroadcast {
+    type Writer = LogBroadcast;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        self.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use tokio::sync::broadcast::error::TryRecvError;
+
+    use crate::log::broadcast::{LogBroadcast, LogSubscription};
+
+    #[test]
+    fn test_broadcast() -> anyhow::Result<()> {
+        let mut broadcast = LogBroadcast::new(2);
+        for line in ["a\n", "b\n", "c\n"] {
+            broadcast.write_all(line.as_bytes())?;
+        }
+
+        // 只保留最近的两条
+        let LogSubscription {
+            recent,
+            receiver: mut rx,
+        } = broadcast.subscribe();
+        assert_eq!(recent, ["b\n".into(), "c\n".into()]);
+        assert_eq!(rx.try_recv(), Err(TryRecvError::Empty));
+
+        broadcast.write_all(b"d\n")?;
+        assert_eq!(&*rx.try_recv()?, "d\n");
+        Ok(())
+    }
+}
@@ -0,0 +1,133 @@
+use std::future::Future;
+
+use tracing_subscriber::fmt::writer::MakeWriterExt;
+use tracing_subscriber::layer::Filter;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{reload, EnvFilter, Layer};
+
+use crate::configure::{on_config_change, Config};
+use crate::log::writer::MakeLogFileWriter;
+
+#[cfg(all(test, feature = "bench"))]
+mod bench;
+pub mod broadcast;
+pub mod query;
+mod retention;
+pub mod writer;
+
+/// 访问日志使用的target，只写入单独的`access-日期.log`
+pub const ACCESS_LOG_TARGET: &str = "access_log";
+/// 日志文件名的前缀
+pub const LOG_FILE_PREFIX: &str = "";
+pub const ACCESS_LOG_FILE_PREFIX: &str = "access-";
+
+/// 输出到控制台的日志
+fn console_layer<S>(
+    filter: impl Filter<S> + Send + Sync + 'static,
+) -> Box<dyn Layer<S> + Send + Sync>
+where
+    S: tracing::Subscriber + for<'a> tracing_subscriber::registry::LookupSpan<'a>,
+{
+    tracing_subscriber::fmt::layer()
+        .compact()
+        .with_ansi(true)
+        .with_filter(filter)
+        .with_filter(tracing_subscriber::filter::filter_fn(|metadata| {
+            metadata.target() != ACCESS_LOG_TARGET
+        }))
+        .boxed()
+}
+
+/// 命令行子命令使用的日志，只输出到控制台，`LOG_LEVEL`没有设置时只显示警告和错误
+pub fn init_console_subscriber() {
+    let filter = std::env::var("LOG_LEVEL")
+        .ok()
+        .and_then(|var| EnvFilter::try_new(var).ok())
+        .unwrap_or_else(|| EnvFilter::new("warn"));
+    tracing_subscriber::registry()
+        .with(console_layer(filter))
+        .init();
+}
+
+// 返回一个Future，用于等待日志文件写入线程写完剩余的日志后结束
+pub fn init_tracing_subscriber(
+    config: &Config,
+) -> anyhow::Result<impl Future<Output = anyhow::Result<()>>> {
+    let mut layers = Vec::with_capacity(3);
+
+    // 过滤规则可以通过重新加载配置修改，规则在读取配置时已经检查过
+    let (console_filter, console_handle) = reload::Layer::new(EnvFilter::new(&config.log.level));
+    let (file_filter, file_handle) = reload::Layer::new(EnvFilter::new(&config.log.file_level));
+    on_config_change(move |config| {
+        writer::update_config(&config.log);
+        console_handle
+            .reload(EnvFilter::new(&config.log.level))
+            .is_ok()
+            && file_handle
+                .reload(EnvFilter::new(&config.log.file_level))
+                .is_ok()
+    });
+
+    layers.push(console_layer(console_filter));
+
+    // 两个写入器共用一个整理协程
+    let opened = retention::spawn(config.storage.logs());
+    let (w, guard) = MakeLogFileWriter::spawn(
+        config.storage.logs(),
+        LOG_FILE_PREFIX,
+        opened.clone(),
+        &config.log,
+    )?;
+
+    layers.push(
+        tracing_subscriber::fmt::layer()
+            .json()
+            .flatten_event(true)
+            .with_ansi(false)
+            .with_file(true)
+            .with_line_number(true)
+            .with_thread_names(true)
+            .with_thread_ids(true)
+            .with_current_span(true)
+            // 写入文件的同时广播给实时订阅日志的客户端
+            .with_writer(w.and(broadcast::init(config.log.tail_buffer)))
+            .with_filter(file_filter)
+            // 过滤掉由`log_file_writer`发出的日志，避免记录自己发出的日志导致死循环
+            // 访问日志写入单独的文件
+            .with_filter(tracing_subscriber::filter::filter_fn(|metadata| {
+                metadata.target() != "log_file_writer" && metadata.target() != ACCESS_LOG_TARGET
+            }))
+            .boxed(),
+    );
+
+    let (access_w, access_guard) = MakeLogFileWriter::spawn(
+        config.storage.logs(),
+        ACCESS_LOG_FILE_PREFIX,
+        opened,
+        &config.log,
+    )?;
+    layers.push(
+        tracing_subscriber::fmt::layer()
+            .json()
+            .flatten_event(true)
+            .with_ansi(false)
+            .with_current_span(true)
+            .with_span_list(false)
+            .with_writer(access_w)
+            // span也需要通过过滤，否则输出时找不到请求的span
+            .with_filter(tracing_subscriber::filter::filter_fn(|metadata| {
+                metadata.is_span() || metadata.target() == ACCESS_LOG_TARGET
+            }))
+            .boxed(),
+    );
+
+    tracing_subscriber::registry().with(layers).init();
+
+    Ok(async move {
+        for guard in [guard, access_guard] {
+            guard.shutdown().await?;
+        }
+        Ok(())
+    })
+}
@@ -0,0 +1,525 @@
+use std::collections::VecDeque;
+use std::fmt::{Display, Formatter};
+use std::fs::File;
+use std::io::{BufRead, BufReader, ErrorKind, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use anyhow::{anyhow, Context};
+use chrono::{DateTime, Duration, Local, NaiveDate, Utc};
+use serde_json::{Map, Value};
+use tracing::Level;
+
+use crate::log::retention::{self, LogFileName};
+
+/// 一次查询最多读取的日志字节数，避免在很大的文件中查找很少见的日志时一直占用线程
+/// 压缩文件中只解压不解析的部分同样计入
+const MAX_SCAN_BYTES: u64 = 64 * 1024 * 1024;
+/// zstd帧头的最大长度
+const ZSTD_FRAME_HEADER_MAX: u64 = 18;
+
+/// 一条日志在日志文件中的位置
+/// 使用解压后的偏移，文件在两次查询之间被压缩也不会失效
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LogCursor {
+    pub date: NaiveDate,
+    pub index: u32,
+    pub offset: u64,
+}
+
+impl LogCursor {
+    fn file(&self) -> (NaiveDate, u32) {
+        (self.date, self.index)
+    }
+}
+
+impl Display for LogCursor {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}.{}:{}",
+            self.date.format("%Y-%m-%d"),
+            self.index,
+            self.offset
+        )
+    }
+}
+
+impl FromStr for LogCursor {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || anyhow!("无效的cursor: {}", s);
+        let (file, offset) = s.split_once(':').ok_or_else(invalid)?;
+        let (date, index) = file.split_once('.').ok_or_else(invalid)?;
+        Ok(LogCursor {
+            date: NaiveDate::parse_from_str(date, "%Y-%m-%d").map_err(|_| invalid())?,
+            index: index.parse().map_err(|_| invalid())?,
+            offset: offset.parse().map_err(|_| invalid())?,
+        })
+    }
+}
+
+/// 一个span中某个字段的值，例如`request_id`
+#[derive(Debug, Clone)]
+pub struct SpanField {
+    pub name: String,
+    pub value: String,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct LogFilter {
+    pub since: Option<DateTime<Utc>>,
+    pub until: Option<DateTime<Utc>>,
+    /// 只包含这个等级以及更严重的日志
+    pub level: Option<Level>,
+    /// target为这个模块或者它的子模块
+    pub target: Option<String>,
+    /// 任意一层span中有这个字段
+    pub span_field: Option<SpanField>,
+    /// 在整行json中搜索
+    pub contains: Option<String>,
+}
+
+impl LogFilter {
+    /// 文件里的日志都在文件日期的本地时间范围内，可以不用读取就跳过整个文件
+    /// 日期切换时正在写入的日志可能会写入下一天的文件，所以结束时间多留一天
+    fn skip_file(&self, date: NaiveDate) -> bool {
+        self.until.is_some_and(|until| {
+            date > until.with_timezone(&Local).date_naive() + Duration::days(1)
+        })
+    }
+
+    /// 文件按日期排序，早于开始时间的文件之前的也都不需要读取
+    fn before_since(&self, date: NaiveDate) -> bool {
+        self.since
+            .is_some_and(|since| date < since.with_timezone(&Local).date_naive())
+    }
+
+    /// 解析一行日志，不符合条件时返回`None`
+    pub fn matches(&self, line: &str) -> Option<LogEntry> {
+        if let Some(contains) = &self.contains {
+            if !line.contains(contains.as_str()) {
+                return None;
+            }
+        }
+        let entry = LogEntry::parse(line)?;
+        if self.since.is_some_and(|since| entry.timestamp < since)
+            || self.until.is_some_and(|until| entry.timestamp > until)
+            || self.level.is_some_and(|level| entry.level > level)
+        {
+            return None;
+        }
+        if let Some(target) = &self.target {
+            let module = entry.target.strip_prefix(target.as_str())?;
+            if !module.is_empty() && !module.starts_with("::") {
+                return None;
+            }
+        }
+        if let Some(field) = &self.span_field {
+            let value = Value::String(field.value.clone());
+            entry
+                .spans()
+                .filter_map(|span| span.get(&field.name))
+                .find(|v| **v == value || v.to_string() == field.value)?;
+        }
+        Some(entry)
+    }
+}
+
+/// 日志文件中的一行
+#[derive(Debug, Clone)]
+pub struct LogEntry {
+    pub timestamp: DateTime<Utc>,
+    pub level: Level,
+    pub target: String,
+    /// 除了`timestamp`、`level`和`target`以外的全部字段
+    pub fields: Map<String, Value>,
+}
+
+impl LogEntry {
+    fn parse(line: &str) -> Option<Self> {
+        let mut fields: Map<String, Value> = serde_json::from_str(line).ok()?;
+        let timestamp = DateTime::parse_from_rfc3339(fields.remove("timestamp")?.as_str()?)
+            .ok()?
+            .with_timezone(&Utc);
+        let level = Level::from_str(fields.remove("level")?.as_str()?).ok()?;
+        let target = match fields.remove("target")? {
+            Value::String(target) => target,
+            _ => return None,
+        };
+        Some(LogEntry {
+            timestamp,
+            level,
+            target,
+            fields,
+        })
+    }
+
+    /// 从外到内的全部span，访问日志只记录了当前span
+    pub fn spans(&self) -> impl Iterator<Item = &Map<String, Value>> {
+        match self.fields.get("spans") {
+            Some(Value::Array(spans)) => spans.iter(),
+            _ => self
+                .fields
+                .get("span")
+                .map(std::slice::from_ref)
+                .unwrap_or_default()
+                .iter(),
+        }
+        .filter_map(Value::as_object)
+    }
+}
+
+pub struct LogPage {
+    pub entries: Vec<(LogCursor, LogEntry)>,
+    /// 下一页的`after`，没有下一页时为`None`
+    /// 读取的日志超过`MAX_SCAN_BYTES`时`entries`可能不满一页，这时它不是最后一条日志的位置
+    pub next_cursor: Option<LogCursor>,
+}
+
+/// 按时间从新到旧查询日志，`after`为上一页的`next_cursor`
+/// 每个文件只读取`after`之前的部分，一次最多读取`MAX_SCAN_BYTES`字节，超过时返回不满一页的结果
+pub fn query(
+    dir: &Path,
+    prefix: &str,
+    filter: &LogFilter,
+    after: Option<LogCursor>,
+    first: usize,
+) -> anyhow::Result<LogPage> {
+    scan(dir, prefix, filter, after, first, MAX_SCAN_BYTES)
+}
+
+fn scan(
+    dir: &Path,
+    prefix: &str,
+    filter: &LogFilter,
+    after: Option<LogCursor>,
+    first: usize,
+    mut budget: u64,
+) -> anyhow::Result<LogPage> {
+    let mut files = match retention::list(dir, prefix) {
+        Ok(files) => files,
+        Err(err) if err.kind() == ErrorKind::NotFound => Vec::new(),
+        Err(err) => return Err(err).with_context(|| "读取日志文件夹失败"),
+    };
+    // 压缩时会短暂地同时存在压缩前后的文件，两者内容相同
+    files.dedup_by_key(|(_, name)| (name.date, name.index));
+
+    let mut entries = Vec::with_capacity(first + 1);
+    for (path, name) in files.into_iter().rev() {
+        if entries.len() > first || filter.before_since(name.date) {
+            break;
+        }
+        let end = match after {
+            Some(after) if (name.date, name.index) > after.file() => continue,
+            Some(after) if (name.date, name.index) == after.file() => after.offset,
+            _ => u64::MAX,
+        };
+        if filter.skip_file(name.date) {
+            continue;
+        }
+        if budget == 0 {
+            return Ok(LogPage {
+                entries,
+                next_cursor: Some(LogCursor {
+                    date: name.date,
+                    index: name.index,
+                    offset: end,
+                }),
+            });
+        }
+
+        // 多读一条用于判断是否还有下一页
+        let limit = first + 1 - entries.len();
+        let mut window = VecDeque::with_capacity(limit);
+        // 一行日志比剩下的上限还长时向前多读，保证`next_cursor`一定会向前推进
+        let mut lookback = budget;
+        let mut skipped = 0;
+        let (mut reader, start, end) = loop {
+            let (reader, start, end, skip) = open_at(&path, name, end, lookback)?;
+            skipped += skip;
+            if start < end || start == 0 {
+                break (reader, start, end);
+            }
+            lookback = lookback.saturating_mul(2);
+        };
+        let mut offset = start;
+        let mut line = Vec::new();
+        while offset < end {
+            line.clear();
+            let len = reader.read_until(b'\n', &mut line)? as u64;
+            // 忽略还没有写完的最后一行
+            if len == 0 || !line.ends_with(b"\n") {
+                break;
+            }
+            let entry = std::str::from_utf8(&line)
+                .ok()
+                .and_then(|line| filter.matches(line));
+            if let Some(entry) = entry {
+                if window.len() == limit {
+                    window.pop_front();
+                }
+                let cursor = LogCursor {
+                    date: name.date,
+                    index: name.index,
+                    offset,
+                };
+                window.push_back((cursor, entry));
+            }
+            offset += len;
+        }
+        budget = budget.saturating_sub(skipped + offset - start);
+        let filled = window.len() == limit;
+        entries.extend(window.into_iter().rev());
+        // 没有从头读取这个文件，并且还不够一页，下一页从读取的位置继续
+        if start > 0 && !filled {
+            return Ok(LogPage {
+                entries,
+                next_cursor: Some(LogCursor {
+                    date: name.date,
+                    index: name.index,
+                    offset: start,
+                }),
+            });
+        }
+    }
+
+    let next_cursor = if entries.len() > first {
+        entries.truncate(first);
+        entries.last().map(|(cursor, _)| *cursor)
+    } else {
+        None
+    };
+    Ok(LogPage {
+        entries,
+        next_cursor,
+    })
+}
+
+/// 打开日志文件并定位到`end`之前`budget`字节处的下一个行首
+/// 返回定位后的偏移、不超过文件长度的`end`以及只解压没有解析的字节数
+/// 压缩过的文件只能从头解压，跳过的部分只解压不解析
+fn open_at(
+    path: &Path,
+    name: LogFileName,
+    end: u64,
+    budget: u64,
+) -> anyhow::Result<(Box<dyn BufRead>, u64, u64, u64)> {
+    let (mut file, name) = open(path, name)?;
+    let mut skipped = 0;
+    let (mut reader, end, start): (Box<dyn BufRead>, _, _) = if name.compressed {
+        let end = match end {
+            u64::MAX => match content_size(&mut file)? {
+                Some(len) => len,
+                // 帧头中没有记录长度的文件只能解压一遍
+                None => {
+                    let len = std::io::copy(&mut zstd::Decoder::new(&file)?, &mut std::io::sink())?;
+                    file.rewind()?;
+                    skipped += len;
+                    len
+                }
+            },
+            end => end,
+        };
+        let start = end.saturating_sub(budget);
+        let mut reader = BufReader::new(zstd::Decoder::new(file)?);
+        skipped += std::io::copy(
+            &mut (&mut reader).take(start.saturating_sub(1)),
+            &mut std::io::sink(),
+        )?;
+        (Box::new(reader), end, start)
+    } else {
+        let end = end.min(file.metadata()?.len());
+        let start = end.saturating_sub(budget);
+        file.seek(SeekFrom::Start(start.saturating_sub(1)))?;
+        (Box::new(BufReader::new(file)), end, start)
+    };
+    if start == 0 {
+        return Ok((reader, 0, end, skipped));
+    }
+    // 从前一个字节开始读到换行，前一个字节刚好是换行时`start`本身就是行首
+    let skipped_line = reader.read_until(b'\n', &mut Vec::new())? as u64;
+    Ok((reader, start - 1 + skipped_line, end, skipped))
+}
+
+/// 读取压缩时记录在zstd帧头中的解压后的长度
+fn content_size(file: &mut File) -> anyhow::Result<Option<u64>> {
+    let mut header = Vec::with_capacity(ZSTD_FRAME_HEADER_MAX as usize);
+    file.by_ref()
+        .take(ZSTD_FRAME_HEADER_MAX)
+        .read_to_end(&mut header)?;
+    file.rewind()?;
+    Ok(zstd::zstd_safe::get_frame_content_size(&header)
+        .ok()
+        .flatten())
+}
+
+/// 打开日志文件，返回实际打开的文件是否被压缩过
+fn open(path: &Path, name: LogFileName) -> anyhow::Result<(File, LogFileName)> {
+    match File::open(path) {
+        // 在列出文件之后文件可能刚好被压缩，这时改为读取压缩后的文件
+        Err(err) if err.kind() == ErrorKind::NotFound && !name.compressed => {
+            let mut compressed = path.as_os_str().to_owned();
+            compressed.push(".zst");
+            let name = LogFileName {
+                compressed: true,
+                ..name
+            };
+            open(&PathBuf::from(compressed), name)
+        }
+        res => Ok((
+            res.with_context(|| format!("读取日志文件{}失败", path.display()))?,
+            name,
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::File;
+    use std::io::Write;
+
+    use chrono::{TimeZone, Utc};
+    use serde_json::json;
+    use tracing::Level;
+
+    use crate::log::query::{content_size, scan, LogCursor, LogFilter, SpanField};
+    use crate::log::retention;
+
+    #[test]
+    fn test_query() -> anyhow::Result<()> {
+        let temp = tempfile::tempdir()?;
+        let dir = temp.path();
+
+        let line = |second: u32, level: &str, target: &str, request_id: Option<&str>| {
+            let mut line = json!({
+                "timestamp": Utc.with_ymd_and_hms(2023, 1, 2, 0, 0, second).unwrap().to_rfc3339(),
+                "level": level,
+                "message": format!("message {}", second),
+                "target": target,
+            });
+            if let Some(request_id) = request_id {
+                line["spans"] = json!([{"name": "request", "request_id": request_id}]);
+            }
+            format!("{}\n", line)
+        };
+        let first_file = [
+            line(0, "INFO", "cat_panel_backend", None),
+            line(1, "DEBUG", "cat_panel_backend::http", Some("a")),
+            line(2, "WARN", "cat_panel_backend::http", Some("b")),
+        ]
+        .concat();
+        // 第一个文件已经被压缩，第二个文件正在写入，最后一行还没有写完
+        std::fs::write(dir.join("2023-01-02.log"), &first_file)?;
+        let compressed = retention::compress(&dir.join("2023-01-02.log"))?;
+        assert_eq!(
+            content_size(&mut File::open(&compressed)?)?,
+            Some(first_file.len() as u64)
+        );
+        std::fs::write(
+            dir.join("2023-01-02.1.log"),
+            [
+                line(3, "ERROR", "sea_orm", None),
+                line(4, "INFO", "cat_panel_backend_other", Some("a")),
+                "{\"timestamp\"".to_owned(),
+            ]
+            .concat(),
+        )?;
+        std::fs::write(
+            dir.join("access-2023-01-02.log"),
+            line(5, "INFO", "access_log", None),
+        )?;
+
+        let scan_messages = |filter: &LogFilter, after, first, budget| -> anyhow::Result<_> {
+            let page = scan(dir, "", filter, after, first, budget)?;
+            let messages = page
+                .entries
+                .iter()
+                .map(|(_, entry)| entry.fields["message"].as_str().unwrap().to_owned())
+                .collect::<Vec<_>>();
+            Ok((messages, page.next_cursor))
+        };
+        let messages = |filter: &LogFilter, after, first| -> anyhow::Result<_> {
+            Ok(scan_messages(filter, after, first, u64::MAX)?.0)
+        };
+
+        // 分页跨过文件
+        let filter = LogFilter::default();
+        let (page, cursor) = scan_messages(&filter, None, 3, u64::MAX)?;
+        assert_eq!(page, ["message 4", "message 3", "message 2"]);
+        let cursor: LogCursor = cursor.unwrap().to_string().parse()?;
+        let (page, cursor) = scan_messages(&filter, Some(cursor), 3, u64::MAX)?;
+        assert_eq!(page, ["message 1", "message 0"]);
+        assert_eq!(cursor, None);
+
+        // 读取的字节数超过上限时返回不满一页的结果，从`next_cursor`继续时不会遗漏或者重复
+        let mut after = None;
+        let mut all = Vec::new();
+        let mut pages = 0;
+        loop {
+            let (page, cursor) = scan_messages(&filter, after, 3, 150)?;
+            assert!(page.len() <= 3);
+            all.extend(page);
+            pages += 1;
+            match cursor {
+                Some(cursor) => after = Some(cursor),
+                None => break,
+            }
+        }
+        assert!(pages > 2);
+        assert_eq!(
+            all,
+            [
+                "message 4",
+                "message 3",
+                "message 2",
+                "message 1",
+                "message 0"
+            ]
+        );
+
+        let filter = LogFilter {
+            level: Some(Level::INFO),
+            target: Some("cat_panel_backend".to_owned()),
+            ..Default::default()
+        };
+        assert_eq!(messages(&filter, None, 10)?, ["message 2", "message 0"]);
+
+        let filter = LogFilter {
+            since: Some(Utc.with_ymd_and_hms(2023, 1, 2, 0, 0, 1).unwrap()),
+            until: Some(Utc.with_ymd_and_hms(2023, 1, 2, 0, 0, 3).unwrap()),
+            ..Default::default()
+        };
+        assert_eq!(
+            messages(&filter, None, 10)?,
+            ["message 3", "message 2", "message 1"]
+        );
+
+        let filter = LogFilter {
+            span_field: Some(SpanField {
+                name: "request_id".to_owned(),
+                value: "a".to_owned(),
+            }),
+            contains: Some("message".to_owned()),
+            ..Default::default()
+        };
+        assert_eq!(messages(&filter, None, 10)?, ["message 4", "message 1"]);
+
+        // 帧头中没有记录长度的文件仍然可以读取
+        let mut encoder = zstd::Encoder::new(File::create(&compressed)?, 0)?;
+        encoder.write_all(first_file.as_bytes())?;
+        encoder.finish()?;
+        assert_eq!(content_size(&mut File::open(&compressed)?)?, None);
+        let (page, cursor) = scan_messages(&LogFilter::default(), None, 5, u64::MAX)?;
+        assert_eq!(page.last().unwrap(), "message 0");
+        assert_eq!(cursor, None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_invalid_cursor() {
+        assert!("2023-01-02.1".parse::<LogCursor>().is_err());
+        assert!("2023-01-02:1".parse::<LogCursor>().is_err());
+    }
+}
@@ -0,0 +1,316 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use chrono::NaiveDate;
+use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
+use tokio::time::MissedTickBehavior;
+
+use crate::configure::{get_config, LogConfig};
+
+const ZSTD_LEVEL: i32 = 3;
+/// 没有切换文件时也定期整理，否则一直不切换文件时按时间和总大小删除不会生效
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// 日志文件名`{prefix}{日期}[.{序号}].log[.zst]`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LogFileName {
+    pub date: NaiveDate,
+    /// 同一天内因为文件大小切换的序号，第一个文件为0
+    pub index: u32,
+    pub compressed: bool,
+}
+
+impl LogFileName {
+    pub fn parse(prefix: &str, name: &str) -> Option<Self> {
+        let (name, compressed) = match name.strip_suffix(".zst") {
+            Some(name) => (name, true),
+            None => (name, false),
+        };
+        let stem = name.strip_prefix(prefix)?.strip_suffix(".log")?;
+        let (date, index) = match stem.split_once('.') {
+            Some((date, index)) => (date, index.parse().ok()?),
+            None => (stem, 0),
+        };
+        Some(LogFileName {
+            date: NaiveDate::parse_from_str(date, "%Y-%m-%d").ok()?,
+            index,
+            compressed,
+        })
+    }
+
+    /// 未压缩时的文件名
+    pub fn file_name(&self, prefix: &str) -> String {
+        match self.index {
+            0 => format!("{}{}.log", prefix, self.date.format("%Y-%m-%d")),
+            index => format!("{}{}.{}.log", prefix, self.date.format("%Y-%m-%d"), index),
+        }
+    }
+}
+
+/// `dir`中属于`prefix`的全部日志文件
+pub fn list(dir: &Path, prefix: &str) -> std::io::Result<Vec<(PathBuf, LogFileName)>> {
+    let mut files = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        if let Some(name) = entry
+            .file_name()
+            .to_str()
+            .and_then(|name| LogFileName::parse(prefix, name))
+        {
+            files.push((entry.path(), name));
+        }
+    }
+    files.sort_by_key(|(_, name)| (name.date, name.index));
+    Ok(files)
+}
+
+/// 日志文件写入器打开了新的文件，之前的文件已经写完
+#[derive(Debug)]
+pub struct Opened {
+    pub prefix: &'static str,
+    pub name: LogFileName,
+}
+
+/// 在单独的task中压缩写完的日志文件并删除过期的日志，不会阻塞日志的写入
+/// 每次打开新文件时以及每隔`SWEEP_INTERVAL`整理一次
+/// 只处理已经打开过文件的写入器的日志，避免压缩写入器马上要继续写入的文件
+pub fn spawn(dir: PathBuf) -> UnboundedSender<Opened> {
+    let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+    tokio::spawn(maintain(dir, rx));
+    tx
+}
+
+async fn maintain(dir: PathBuf, mut rx: UnboundedReceiver<Opened>) {
+    let mut active = HashMap::new();
+    let mut interval = tokio::time::interval(SWEEP_INTERVAL);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    loop {
+        tokio::select! {
+            opened = rx.recv() => match opened {
+                Some(opened) => {
+                    active.insert(opened.prefix, opened.name);
+                }
+                None => break,
+            },
+            _ = interval.tick() => {}
+        }
+        // 合并处理期间收到的消息
+        while let Ok(opened) = rx.try_recv() {
+            active.insert(opened.prefix, opened.name);
+        }
+        if active.is_empty() {
+            continue;
+        }
+
+        let dir = dir.clone();
+        let active = active.clone();
+        let config = get_config().log.clone();
+        let res = tokio::task::spawn_blocking(move || sweep(&dir, &active, &config)).await;
+        if let Ok(Err(err)) = res {
+            tracing::error!(target: "log_file_writer", "整理日志文件失败: {}", err);
+        }
+    }
+}
+
+/// 压缩除了正在写入的文件以外的全部日志，然后按时间和总大小删除旧的日志
+/// 写入器只会向后切换文件，所以`active`及之后的文件都可能正在写入，不会被处理，但计入总大小
+fn sweep(
+    dir: &Path,
+    active: &HashMap<&'static str, LogFileName>,
+    config: &LogConfig,
+) -> anyhow::Result<()> {
+    // 删除上次压缩到一半时留下的临时文件
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name();
+        let leftover = name.to_str().and_then(|name| name.strip_suffix(".zst.tmp"));
+        if let Some(name) = leftover {
+            if active
+                .keys()
+                .any(|prefix| LogFileName::parse(prefix, name).is_some())
+            {
+                if let Err(err) = std::fs::remove_file(entry.path()) {
+                    tracing::error!(target: "log_file_writer", "删除{}失败: {}", entry.path().display(), err);
+                }
+            }
+        }
+    }
+
+    let mut total_size = 0;
+    let mut files = Vec::new();
+    for (prefix, active) in active {
+        for (path, name) in list(dir, prefix)? {
+            if (name.date, name.index) >= (active.date, active.index) {
+                match std::fs::metadata(&path) {
+                    Ok(metadata) => total_size += metadata.len(),
+                    Err(err) => {
+                        tracing::error!(target: "log_file_writer", "读取{}失败: {}", path.display(), err)
+                    }
+                }
+                continue;
+            }
+            // 单个文件压缩失败时保留原来的文件继续处理，不影响其他文件的压缩和删除
+            let path = if config.compress && !name.compressed {
+                match compress(&path) {
+                    Ok(zst) => zst,
+                    Err(err) => {
+                        tracing::error!(target: "log_file_writer", "压缩{}失败: {:#}", path.display(), err);
+                        path
+                    }
+                }
+            } else {
+                path
+            };
+            // 单个文件读取或者删除失败时同样只跳过这个文件
+            match std::fs::metadata(&path)
+                .and_then(|metadata| Ok((metadata.len(), metadata.modified()?)))
+            {
+                Ok((size, modified)) => files.push((path, size, modified)),
+                Err(err) => {
+                    tracing::error!(target: "log_file_writer", "读取{}失败: {}", path.display(), err)
+                }
+            }
+        }
+    }
+    // 从最旧的文件开始删除
+    files.sort_by_key(|(_, _, modified)| *modified);
+
+    let now = SystemTime::now();
+    let max_total_size = config.max_total_size.get_bytes();
+    total_size += files.iter().map(|(_, size, _)| size).sum::<u64>();
+    for (path, size, modified) in files {
+        let expired = !config.max_age.is_zero()
+            && now.duration_since(modified).unwrap_or_default() > config.max_age;
+        let oversize = max_total_size != 0 && total_size > max_total_size;
+        if expired || oversize {
+            match std::fs::remove_file(&path) {
+                Ok(()) => total_size -= size,
+                Err(err) => {
+                    tracing::error!(target: "log_file_writer", "删除{}失败: {}", path.display(), err)
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// 压缩为`.zst`并删除原来的文件，先写入临时文件，压缩到一半时退出不会留下损坏的`.zst`
+pub(super) fn compress(path: &Path) -> anyhow::Result<PathBuf> {
+    let mut zst = path.as_os_str().to_owned();
+    zst.push(".zst");
+    let zst = PathBuf::from(zst);
+    let tmp = zst.with_extension("zst.tmp");
+
+    let src = File::open(path)?;
+    let mut encoder = zstd::Encoder::new(BufWriter::new(File::create(&tmp)?), ZSTD_LEVEL)?;
+    // 在帧头中记录解压后的长度，查询时不需要为了得到长度解压整个文件
+    encoder.set_pledged_src_size(Some(src.metadata()?.len()))?;
+    encoder.include_contentsize(true)?;
+    std::io::copy(&mut BufReader::new(src), &mut encoder)?;
+    let mut file = encoder.finish()?.into_inner()?;
+    file.flush()?;
+    file.sync_all()?;
+    // 保留原来的修改时间，按时间删除时才不会把压缩的时间当作最后写入的时间
+    file.set_modified(std::fs::metadata(path)?.modified()?)?;
+    std::fs::rename(&tmp, &zst)?;
+    std::fs::remove_file(path)?;
+    Ok(zst)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::time::{Duration, SystemTime};
+
+    use byte_unit::Byte;
+    use chrono::NaiveDate;
+
+    use crate::configure::{get_config, init_test_configure};
+    use crate::log::retention::{list, sweep, LogFileName};
+
+    #[test]
+    fn test_parse_file_name() {
+        let date = NaiveDate::from_ymd_opt(2023, 1, 2).unwrap();
+        let name = |index, compressed| {
+            Some(LogFileName {
+                date,
+                index,
+                compressed,
+            })
+        };
+        assert_eq!(LogFileName::parse("", "2023-01-02.log"), name(0, false));
+        assert_eq!(
+            LogFileName::parse("", "2023-01-02.3.log.zst"),
+            name(3, true)
+        );
+        assert_eq!(
+            LogFileName::parse("access-", "access-2023-01-02.1.log"),
+            name(1, false)
+        );
+        assert_eq!(LogFileName::parse("", "access-2023-01-02.log"), None);
+        assert_eq!(LogFileName::parse("", "2023-01-02.log.zst.tmp"), None);
+        assert_eq!(
+            name(2, false).unwrap().file_name("access-"),
+            "access-2023-01-02.2.log"
+        );
+    }
+
+    #[test]
+    fn test_sweep() -> anyhow::Result<()> {
+        let _config = init_test_configure()?;
+        let temp = tempfile::tempdir()?;
+        let dir = temp.path();
+        let old = SystemTime::now() - Duration::from_secs(60 * 60 * 24 * 10);
+        for (name, modified) in [
+            ("2023-01-01.log", old),
+            ("2023-01-02.log", SystemTime::now()),
+            ("2023-01-02.1.log", SystemTime::now()),
+            ("2023-01-02.2.log", SystemTime::now()),
+            ("access-2023-01-02.log", SystemTime::now()),
+            ("2023-01-01.1.log.zst.tmp", SystemTime::now()),
+        ] {
+            let file = std::fs::File::create(dir.join(name))?;
+            std::io::Write::write_all(&mut &file, &[b'a'; 4096])?;
+            file.set_modified(modified)?;
+        }
+
+        let mut config = get_config().log.clone();
+        config.compress = true;
+        config.max_age = Duration::from_secs(60 * 60 * 24 * 7);
+        config.max_total_size = Byte::from_bytes(0);
+        let active = HashMap::from([(
+            "",
+            LogFileName {
+                date: NaiveDate::from_ymd_opt(2023, 1, 2).unwrap(),
+                index: 1,
+                compressed: false,
+            },
+        )]);
+        sweep(dir, &active, &config)?;
+
+        let names = |prefix| -> anyhow::Result<Vec<String>> {
+            Ok(list(dir, prefix)?
+                .into_iter()
+                .map(|(path, _)| path.file_name().unwrap().to_string_lossy().into_owned())
+                .collect())
+        };
+        // 过期的被删除，写完的被压缩，正在写入的、之后的和其他写入器的文件不变
+        assert_eq!(
+            names("")?,
+            ["2023-01-02.log.zst", "2023-01-02.1.log", "2023-01-02.2.log"]
+        );
+        assert_eq!(names("access-")?, ["access-2023-01-02.log"]);
+        assert!(!dir.join("2023-01-01.1.log.zst.tmp").exists());
+        let decoded = zstd::decode_all(std::fs::File::open(dir.join("2023-01-02.log.zst"))?)?;
+        assert_eq!(decoded.len(), 4096);
+
+        // 超过总大小时从最旧的开始删除，但不会删除正在写入的文件
+        config.max_total_size = Byte::from_bytes(1);
+        sweep(dir, &active, &config)?;
+        assert_eq!(names("")?, ["2023-01-02.1.log", "2023-01-02.2.log"]);
+        Ok(())
+    }
+}
@@ -0,0 +1,440 @@
+use std::fs::{create_dir_all, File, OpenOptions};
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc::{Receiver, SyncSender, TrySendError};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+
+use anyhow::Context;
+use chrono::{Local, NaiveDate};
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use tokio::runtime::{Handle, RuntimeFlavor};
+use tokio::sync::mpsc::UnboundedSender;
+use tracing_subscriber::fmt::MakeWriter;
+
+use crate::configure::{LogConfig, OverflowPolicy};
+use crate::log::retention::{self, LogFileName, Opened};
+
+/// 写入线程每批最多处理的行数，每批结束时flush一次
+const MAX_BATCH: usize = 1024;
+const BUF_CAPACITY: usize = 64 * 1024;
+
+/// 全部日志文件写入器的状态，用于更新配置和读取统计
+static WRITERS: Lazy<Mutex<Vec<Arc<Shared>>>> = Lazy::new(Default::default);
+
+enum Msg {
+    Line(Vec<u8>),
+    Shutdown,
+}
+
+/// 写入线程和写入端共享的状态
+struct Shared {
+    prefix: &'static str,
+    drop_on_overflow: AtomicBool,
+    max_file_size: AtomicU64,
+    written_lines: AtomicU64,
+    dropped_lines: AtomicU64,
+}
+
+impl Shared {
+    fn update(&self, config: &LogConfig) {
+        self.drop_on_overflow
+            .store(config.overflow == OverflowPolicy::Drop, Ordering::Relaxed);
+        self.max_file_size
+            .store(config.max_file_size.get_bytes(), Ordering::Relaxed);
+    }
+}
+
+/// 一个日志文件写入器的统计
+#[derive(Debug, Clone, Copy)]
+pub struct WriterStats {
+    /// 日志文件名的前缀
+    pub prefix: &'static str,
+    pub written_lines: u64,
+    /// 因为队列已满而丢弃的行数
+    pub dropped_lines: u64,
+}
+
+pub fn stats() -> Vec<WriterStats> {
+    WRITERS
+        .lock()
+        .iter()
+        .map(|shared| WriterStats {
+            prefix: shared.prefix,
+            written_lines: shared.written_lines.load(Ordering::Relaxed),
+            dropped_lines: shared.dropped_lines.load(Ordering::Relaxed),
+        })
+        .collect()
+}
+
+/// 配置重新加载后更新溢出策略和文件大小，队列容量需要重启才能修改
+pub fn update_config(config: &LogConfig) {
+    for shared in WRITERS.lock().iter() {
+        shared.update(config);
+    }
+}
+
+/// 把日志放入队列，由单独的写入线程按顺序批量写入文件，不会为每一行日志创建任务
+#[derive(Clone)]
+pub struct MakeLogFileWriter {
+    sender: SyncSender<Msg>,
+    shared: Arc<Shared>,
+}
+
+/// 用于关闭写入线程
+pub struct WriterGuard {
+    sender: SyncSender<Msg>,
+    thread: JoinHandle<()>,
+}
+
+impl MakeLogFileWriter {
+    /// 打开今天的日志文件并启动写入线程
+    pub fn spawn(
+        dir: PathBuf,
+        prefix: &'static str,
+        opened: UnboundedSender<Opened>,
+        config: &LogConfig,
+    ) -> anyhow::Result<(Self, WriterGuard)> {
+        let shared = Arc::new(Shared {
+            prefix,
+            drop_on_overflow: AtomicBool::new(false),
+            max_file_size: AtomicU64::new(0),
+            written_lines: AtomicU64::new(0),
+            dropped_lines: AtomicU64::new(0),
+        });
+        shared.update(config);
+
+        let file = LogFile::new(dir, prefix, opened, shared.clone())?;
+        let (sender, receiver) = std::sync::mpsc::sync_channel(config.queue_capacity);
+        let thread = std::thread::Builder::new()
+            .name(format!("log-writer-{}", prefix.trim_end_matches('-')))
+            .spawn(move || file.run(receiver))?;
+        WRITERS.lock().push(shared.clone());
+
+        Ok((
+            MakeLogFileWriter {
+                sender: sender.clone(),
+                shared,
+            },
+            WriterGuard { sender, thread },
+        ))
+    }
+}
+
+impl WriterGuard {
+    /// 等待队列里的日志全部写入后关闭写入线程
+    pub async fn shutdown(self) -> anyhow::Result<()> {
+        tokio::task::spawn_blocking(move || self.close()).await?
+    }
+
+    fn close(self) -> anyhow::Result<()> {
+        let _ = self.sender.send(Msg::Shutdown);
+        self.thread
+            .join()
+            .map_err(|_| anyhow::anyhow!("日志文件写入线程panic"))
+    }
+}
+
+impl<'a> MakeWriter<'a> for MakeLogFileWriter {
+    type Writer = Self;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        self.clone()
+    }
+}
+
+impl Write for MakeLogFileWriter {
+    /// fmt层每条日志只会写入一次，`buf`是完整的一行
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let res = match self.sender.try_send(Msg::Line(buf.to_vec())) {
+            Err(TrySendError::Full(msg))
+                if !self.shared.drop_on_overflow.load(Ordering::Relaxed) =>
+            {
+                match Handle::try_current().map(|handle| handle.runtime_flavor()) {
+                    // 工作线程上的其他任务会先交给别的线程，spawn_blocking的线程中直接等待
+                    Ok(RuntimeFlavor::MultiThread) => {
+                        tokio::task::block_in_place(|| self.sender.send(msg).map_err(|err| err.0))
+                    }
+                    // 单线程运行时中等待会拖住全部任务，即使是`block`也只能丢弃
+                    Ok(_) => {
+                        self.shared.dropped_lines.fetch_add(1, Ordering::Relaxed);
+                        Ok(())
+                    }
+                    Err(_) => self.sender.send(msg).map_err(|err| err.0),
+                }
+            }
+            Err(TrySendError::Full(_)) => {
+                self.shared.dropped_lines.fetch_add(1, Ordering::Relaxed);
+                Ok(())
+            }
+            Err(TrySendError::Disconnected(msg)) => Err(msg),
+            Ok(()) => Ok(()),
+        };
+        // 这里本身就在tracing的写入过程中，再记录日志可能重入，直接写到stderr
+        if let Err(Msg::Line(line)) = res {
+            eprintln!(
+                "日志文件写入器已经关闭但仍然试图写入: {:?}",
+                String::from_utf8_lossy(&line)
+            );
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// 在写入线程中使用的日志文件，按日期和大小切换文件
+struct LogFile {
+    dir: PathBuf,
+    /// 文件名的前缀，文件名为`{prefix}{日期}[.{序号}].log`
+    prefix: &'static str,
+    file: BufWriter<File>,
+    date: NaiveDate,
+    /// 同一天内因为文件大小切换的序号
+    index: u32,
+    /// 当前文件已经写入的大小
+    size: u64,
+    /// 打开新文件时通知日志整理协程
+    opened: UnboundedSender<Opened>,
+    shared: Arc<Shared>,
+}
+
+impl LogFile {
+    fn new(
+        dir: PathBuf,
+        prefix: &'static str,
+        opened: UnboundedSender<Opened>,
+        shared: Arc<Shared>,
+    ) -> anyhow::Result<Self> {
+        create_dir_all(&dir).with_context(|| "创建日志文件夹失败")?;
+
+        // 继续写入今天最后一个日志文件，已经被压缩的话则写入下一个
+        let today = Local::now().date_naive();
+        let files = retention::list(&dir, prefix).with_context(|| "读取日志文件夹失败")?;
+        let index = match files.iter().rev().find(|(_, name)| name.date == today) {
+            Some((_, name)) if name.compressed => name.index + 1,
+            Some((_, name)) => name.index,
+            None => 0,
+        };
+
+        let (file, size) = Self::open(&dir, prefix, today, index)?;
+        let file = LogFile {
+            dir,
+            prefix,
+            file,
+            date: today,
+            index,
+            size,
+            opened,
+            shared,
+        };
+        file.notify_opened();
+        Ok(file)
+    }
+
+    fn open(
+        dir: &Path,
+        prefix: &str,
+        date: NaiveDate,
+        index: u32,
+    ) -> anyhow::Result<(BufWriter<File>, u64)> {
+        let name = LogFileName {
+            date,
+            index,
+            compressed: false,
+        };
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(dir.join(name.file_name(prefix)))
+            .with_context(|| "打开日志文件失败")?;
+        let size = file.metadata()?.len();
+        Ok((BufWriter::with_capacity(BUF_CAPACITY, file), size))
+    }
+
+    fn notify_opened(&self) {
+        // 整理协程只有在运行时关闭时才会结束，这时不需要再整理
+        let _ = self.opened.send(Opened {
+            prefix: self.prefix,
+            name: LogFileName {
+                date: self.date,
+                index: self.index,
+                compressed: false,
+            },
+        });
+    }
+
+    /// 日期变化或者文件大小超过限制时切换到新的日志文件
+    fn rotate(&mut self, len: usize) -> anyhow::Result<()> {
+        let today = Local::now().date_naive();
+        let index = if today != self.date {
+            0
+        } else if self.size > 0
+            && self.size + len as u64 > self.shared.max_file_size.load(Ordering::Relaxed)
+        {
+            self.index + 1
+        } else {
+            return Ok(());
+        };
+
+        // 确保旧文件写完后才交给整理协程压缩
+        self.file.flush()?;
+        let (file, size) = Self::open(&self.dir, self.prefix, today, index)?;
+        self.file = file;
+        self.size = size;
+        self.date = today;
+        self.index = index;
+        self.notify_opened();
+        Ok(())
+    }
+
+    fn write(&mut self, buf: &[u8]) -> anyhow::Result<()> {
+        self.rotate(buf.len())?;
+        self.file.write_all(buf)?;
+        self.size += buf.len() as u64;
+        Ok(())
+    }
+
+    /// 一次取出队列里已有的日志批量写入，每批结束时flush，收到`Shutdown`时写完剩余的日志后结束
+    fn run(mut self, receiver: Receiver<Msg>) {
+        let mut shutdown = false;
+        let mut reported_dropped = 0;
+        while !shutdown {
+            let Ok(msg) = receiver.recv() else { break };
+            let mut next = Some(msg);
+            let mut written = 0;
+            while let Some(msg) = next.take() {
+                match msg {
+                    Msg::Line(line) => {
+                        if let Err(err) = self.write(&line) {
+                            tracing::error!(target: "log_file_writer", "写入日志文件时发生错误: {}", err);
+                        }
+                        written += 1;
+                    }
+                    Msg::Shutdown => shutdown = true,
+                }
+                // 关闭时不再限制批量大小，写完关闭前已经发送的日志
+                if shutdown || written < MAX_BATCH {
+                    next = receiver.try_recv().ok();
+                }
+            }
+            if let Err(err) = self.file.flush() {
+                tracing::error!(target: "log_file_writer", "写入日志文件时发生错误: {}", err);
+            }
+            self.shared
+                .written_lines
+                .fetch_add(written as u64, Ordering::Relaxed);
+
+            // 只输出到控制台
+            let dropped = self.shared.dropped_lines.load(Ordering::Relaxed);
+            if dropped > reported_dropped {
+                tracing::warn!(target: "log_file_writer", "日志文件写入跟不上，丢弃了{}行日志，文件前缀: {:?}", dropped - reported_dropped, self.prefix);
+                reported_dropped = dropped;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+    use std::sync::atomic::Ordering;
+
+    use crate::configure::{get_config, init_test_configure, OverflowPolicy};
+    use crate::log::retention::list;
+    use crate::log::writer::MakeLogFileWriter;
+
+    #[test]
+    fn test_writer() -> anyhow::Result<()> {
+        let _config = init_test_configure()?;
+        let temp = tempfile::tempdir()?;
+        let dir = temp.path().to_owned();
+        let (opened, _rx) = tokio::sync::mpsc::unbounded_channel();
+        let read_lines = |prefix| -> anyhow::Result<Vec<usize>> {
+            let (path, _) = list(&dir, prefix)?.pop().unwrap();
+            Ok(std::fs::read_to_string(path)?
+                .lines()
+                .map(|line| line.parse().unwrap())
+                .collect())
+        };
+
+        // 阻塞时不会丢失日志，并且按写入的顺序写入文件
+        let mut config = get_config().log.clone();
+        config.queue_capacity = 4;
+        let (mut w, guard) =
+            MakeLogFileWriter::spawn(dir.clone(), "block-", opened.clone(), &config)?;
+        for i in 0..1000 {
+            w.write_all(format!("{}\n", i).as_bytes())?;
+        }
+        guard.close()?;
+        assert_eq!(read_lines("block-")?, (0..1000).collect::<Vec<_>>());
+        assert_eq!(w.shared.written_lines.load(Ordering::Relaxed), 1000);
+
+        // 丢弃时写入的和丢弃的加起来等于全部日志，写入的仍然保持顺序
+        config.overflow = OverflowPolicy::Drop;
+        let (mut w, guard) = MakeLogFileWriter::spawn(dir.clone(), "drop-", opened, &config)?;
+        for i in 0..1000 {
+            w.write_all(format!("{}\n", i).as_bytes())?;
+        }
+        guard.close()?;
+        let lines = read_lines("drop-")?;
+        assert!(lines.windows(2).all(|pair| pair[0] < pair[1]));
+        let written = w.shared.written_lines.load(Ordering::Relaxed);
+        let dropped = w.shared.dropped_lines.load(Ordering::Relaxed);
+        assert_eq!(written, lines.len() as u64);
+        assert_eq!(written + dropped, 1000);
+
+        // 多线程运行时的工作线程和spawn_blocking的线程中`block`同样不会丢失日志
+        config.overflow = OverflowPolicy::Block;
+        let (opened, _rx) = tokio::sync::mpsc::unbounded_channel();
+        let (w, guard) = MakeLogFileWriter::spawn(dir.clone(), "worker-", opened, &config)?;
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(2)
+            .build()?;
+        let mut worker_w = w.clone();
+        let mut blocking_w = w.clone();
+        runtime.block_on(async move {
+            tokio::spawn(async move {
+                for i in 0..500 {
+                    worker_w.write_all(format!("{}\n", i).as_bytes())?;
+                }
+                anyhow::Ok(())
+            })
+            .await??;
+            tokio::task::spawn_blocking(move || {
+                for i in 500..1000 {
+                    blocking_w.write_all(format!("{}\n", i).as_bytes())?;
+                }
+                anyhow::Ok(())
+            })
+            .await??;
+            anyhow::Ok(())
+        })?;
+        guard.close()?;
+        assert_eq!(read_lines("worker-")?, (0..1000).collect::<Vec<_>>());
+        assert_eq!(w.shared.dropped_lines.load(Ordering::Relaxed), 0);
+
+        // 单线程运行时中`block`不会阻塞，写不下的同样计入丢弃
+        let (opened, _rx) = tokio::sync::mpsc::unbounded_channel();
+        let (w, guard) = MakeLogFileWriter::spawn(dir.clone(), "runtime-", opened, &config)?;
+        let mut runtime_w = w.clone();
+        tokio::runtime::Builder::new_current_thread()
+            .build()?
+            .block_on(async move {
+                for i in 0..1000 {
+                    runtime_w.write_all(format!("{}\n", i).as_bytes())?;
+                }
+                anyhow::Ok(())
+            })?;
+        guard.close()?;
+        let written = w.shared.written_lines.load(Ordering::Relaxed);
+        let dropped = w.shared.dropped_lines.load(Ordering::Relaxed);
+        assert_eq!(written, read_lines("runtime-")?.len() as u64);
+        assert_eq!(written + dropped, 1000);
+        Ok(())
+    }
+}
@@ -1,42 +1,68 @@
+#![cfg_attr(all(test, feature = "bench"), feature(test))]
+
 use std::time::Duration;
 
-use tracing::info;
+use clap::Parser;
+use tracing::{info, warn};
 
+use crate::cli::{Cli, Command};
+use crate::configure::{get_config, init_configure, override_data_dir, watch_config};
 use crate::environment::init_environment;
 use crate::http::start_http_server;
 use crate::log::init_tracing_subscriber;
 
+mod cli;
 mod configure;
+mod database;
+mod environment;
 mod http;
 mod log;
-mod environment;
+mod user;
 
 #[global_allocator]
 static ALLOC: snmalloc_rs::SnMalloc = snmalloc_rs::SnMalloc;
 
-async fn main2() -> anyhow::Result<()> {
-    let wait_for_shutdown = init_tracing_subscriber();
-    init_environment()?;
+async fn main2(cli: Cli) -> anyhow::Result<()> {
+    if let Some(data_dir) = cli.data_dir {
+        override_data_dir(data_dir);
+    }
+    match cli.command.unwrap_or(Command::Serve) {
+        Command::Serve => serve().await,
+        command => cli::run(command).await,
+    }
+}
+
+async fn serve() -> anyhow::Result<()> {
+    // 日志目录由配置决定，所以需要先读取配置
+    let outcome = init_configure()?;
+    let wait_for_shutdown = init_tracing_subscriber(&get_config())?;
+    if let Some(path) = outcome.dotenv {
+        info!("load {}", path.display());
+    }
+    for issue in outcome.warnings {
+        warn!("{}", issue);
+    }
+    init_environment().await?;
 
     info!("Hello, world!");
 
     tokio_graceful_shutdown::Toplevel::new()
         .start("http server", start_http_server)
+        .start("config watcher", watch_config)
         .catch_signals()
         .handle_shutdown_requests(Duration::from_secs(3))
         .await?;
 
-    // 在tokio_graceful_shutdown关闭后等待
-    // 因为异步写入的情况下有时候会出现日志文件写入器已经关闭而前面的日志才发送的情况
-    tokio::time::sleep(Duration::from_millis(200)).await;
+    // 日志在写入时已经按顺序放入队列，关闭时写入线程会写完队列里的日志
     wait_for_shutdown.await?;
 
     Ok(())
 }
 
 fn main() -> anyhow::Result<()> {
+    let cli = Cli::parse();
     tokio::runtime::Builder::new_multi_thread()
         .enable_all()
         .build()?
-        .block_on(main2())
+        .block_on(main2(cli))
 }
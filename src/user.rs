@@ -0,0 +1,234 @@
+use anyhow::{bail, Context};
+use argon2::password_hash::SaltString;
+use argon2::{Argon2, PasswordHash, PasswordHasher, PasswordVerifier};
+use axum_sessions::async_session::base64;
+use axum_sessions::async_session::sha2::{Digest, Sha256};
+use chrono::Utc;
+use rand::distributions::{Alphanumeric, DistString};
+use sea_orm::{
+    ActiveModelTrait, ColumnTrait, ConnectionTrait, EntityTrait, PaginatorTrait, QueryFilter, Set,
+};
+use tokio::sync::OnceCell;
+use tracing::{info, warn};
+
+use crate::database::entity::user;
+
+/// 第一次启动时创建的管理员账号的用户名
+pub const ADMIN_USERNAME: &str = "admin";
+/// 可以通过这个环境变量指定初始管理员密码，没有指定则随机生成
+const ADMIN_PASSWORD_ENV: &str = "CP_ADMIN_PASSWORD";
+
+/// 用户名不存在时用来校验的hash，与真实的hash使用相同的参数
+static DUMMY_HASH: OnceCell<String> = OnceCell::const_new();
+
+/// 使用argon2id计算密码的hash
+/// 计算比较耗时，所以放在tokio的阻塞线程池中执行
+pub async fn hash_password(password: String) -> anyhow::Result<String> {
+    tokio::task::spawn_blocking(move || {
+        let salt = SaltString::generate(&mut rand::thread_rng());
+        Argon2::default()
+            .hash_password(password.as_bytes(), &salt)
+            .map(|hash| hash.to_string())
+            .map_err(|err| anyhow::anyhow!("计算密码hash失败: {}", err))
+    })
+    .await?
+}
+
+/// 校验密码是否与保存的hash匹配
+pub async fn verify_password(password: String, password_hash: String) -> anyhow::Result<bool> {
+    tokio::task::spawn_blocking(move || {
+        let hash = PasswordHash::new(&password_hash)
+            .map_err(|err| anyhow::anyhow!("无法解析密码hash: {}", err))?;
+        Ok(Argon2::default()
+            .verify_password(password.as_bytes(), &hash)
+            .is_ok())
+    })
+    .await?
+}
+
+/// 用户的凭据版本，登录时保存在session里
+/// 每次修改密码都会生成新的盐，所以密码hash的摘要在修改密码后一定会变化
+pub fn credential_version(user: &user::Model) -> String {
+    base64::encode(&Sha256::digest(user.password_hash.as_bytes())[..16])
+}
+
+pub async fn find_by_username(
+    db: &impl ConnectionTrait,
+    username: &str,
+) -> anyhow::Result<Option<user::Model>> {
+    user::Entity::find()
+        .filter(user::Column::Username.eq(username))
+        .one(db)
+        .await
+        .map_err(Into::into)
+}
+
+/// 校验用户名和密码，成功则返回对应的用户
+pub async fn authenticate(
+    db: &impl ConnectionTrait,
+    username: &str,
+    password: String,
+) -> anyhow::Result<Option<user::Model>> {
+    let user = match find_by_username(db, username).await? {
+        Some(user) => user,
+        None => {
+            // 同样计算一次hash，避免通过响应时间判断用户名是否存在
+            let hash = DUMMY_HASH
+                .get_or_try_init(|| {
+                    hash_password(Alphanumeric.sample_string(&mut rand::thread_rng(), 32))
+                })
+                .await?;
+            verify_password(password, hash.clone()).await?;
+            return Ok(None);
+        }
+    };
+    Ok(verify_password(password, user.password_hash.clone())
+        .await?
+        .then_some(user))
+}
+
+pub async fn create_user(
+    db: &impl ConnectionTrait,
+    username: &str,
+    password: String,
+) -> anyhow::Result<user::Model> {
+    if find_by_username(db, username).await?.is_some() {
+        bail!("用户{}已经存在", username);
+    }
+    let now = Utc::now();
+    user::ActiveModel {
+        username: Set(username.to_owned()),
+        password_hash: Set(hash_password(password).await?),
+        created_at: Set(now),
+        updated_at: Set(now),
+        ..Default::default()
+    }
+    .insert(db)
+    .await
+    .with_context(|| format!("创建用户{}失败", username))
+}
+
+/// 修改用户密码，用户不存在时返回错误
+pub async fn set_password(
+    db: &impl ConnectionTrait,
+    username: &str,
+    password: String,
+) -> anyhow::Result<user::Model> {
+    let user = match find_by_username(db, username).await? {
+        Some(user) => user,
+        None => bail!("用户{}不存在", username),
+    };
+    let mut user: user::ActiveModel = user.into();
+    user.password_hash = Set(hash_password(password).await?);
+    user.updated_at = Set(Utc::now());
+    user.update(db)
+        .await
+        .with_context(|| format!("修改用户{}的密码失败", username))
+}
+
+/// 删除用户，用户不存在时返回错误
+pub async fn delete_user(db: &impl ConnectionTrait, username: &str) -> anyhow::Result<user::Model> {
+    let user = match find_by_username(db, username).await? {
+        Some(user) => user,
+        None => bail!("用户{}不存在", username),
+    };
+    user::Entity::delete_by_id(user.id).exec(db).await?;
+    Ok(user)
+}
+
+/// 如果数据库里还没有任何用户，则创建初始管理员账号
+/// 密码优先读取`CP_ADMIN_PASSWORD`，否则随机生成并只在控制台输出这一次
+pub async fn init_admin(db: &impl ConnectionTrait) -> anyhow::Result<()> {
+    init_admin_with(db, std::env::var(ADMIN_PASSWORD_ENV).ok()).await
+}
+
+/// `password`为`CP_ADMIN_PASSWORD`的值，空的或者只有空白字符时返回错误而不是随机生成
+async fn init_admin_with(
+    db: &impl ConnectionTrait,
+    password: Option<String>,
+) -> anyhow::Result<()> {
+    if user::Entity::find().count(db).await? > 0 {
+        return Ok(());
+    }
+
+    match password {
+        Some(password) if password.trim().is_empty() => {
+            bail!("{}不能为空，不设置时会随机生成密码", ADMIN_PASSWORD_ENV)
+        }
+        Some(password) => {
+            create_user(db, ADMIN_USERNAME, password).await?;
+            info!(
+                "已使用{}创建初始管理员账号{}",
+                ADMIN_PASSWORD_ENV, ADMIN_USERNAME
+            );
+        }
+        None => {
+            let password = Alphanumeric.sample_string(&mut rand::thread_rng(), 16);
+            create_user(db, ADMIN_USERNAME, password.clone()).await?;
+            // 日志会写入文件并且可以通过api查询，密码只能直接输出到控制台
+            eprintln!(
+                "已创建初始管理员账号{}, 密码: {} (只会显示这一次，请登录后尽快修改)",
+                ADMIN_USERNAME, password
+            );
+            warn!(
+                "已创建初始管理员账号{}, 随机生成的密码已输出到控制台",
+                ADMIN_USERNAME
+            );
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::database::connect;
+    use crate::user::{
+        authenticate, create_user, credential_version, delete_user, find_by_username, init_admin,
+        init_admin_with, set_password, verify_password, ADMIN_USERNAME,
+    };
+
+    #[tokio::test]
+    async fn test_create_and_verify() -> anyhow::Result<()> {
+        let db = connect("sqlite::memory:".parse()?).await?;
+        init_admin(&db).await?;
+        assert!(find_by_username(&db, ADMIN_USERNAME).await?.is_some());
+
+        let user = create_user(&db, "cat", "meow".into()).await?;
+        assert!(verify_password("meow".into(), user.password_hash.clone()).await?);
+        assert!(!verify_password("woof".into(), user.password_hash.clone()).await?);
+        assert!(create_user(&db, "cat", "meow".into()).await.is_err());
+
+        assert!(authenticate(&db, "cat", "meow".into()).await?.is_some());
+        assert!(authenticate(&db, "cat", "woof".into()).await?.is_none());
+        assert!(authenticate(&db, "dog", "meow".into()).await?.is_none());
+
+        // 修改密码后凭据版本变化，即使密码和之前相同
+        let version = credential_version(&user);
+        let user = set_password(&db, "cat", "meow".into()).await?;
+        assert_ne!(credential_version(&user), version);
+
+        set_password(&db, "cat", "purr".into()).await?;
+        assert!(authenticate(&db, "cat", "meow".into()).await?.is_none());
+        assert!(authenticate(&db, "cat", "purr".into()).await?.is_some());
+
+        delete_user(&db, "cat").await?;
+        assert!(find_by_username(&db, "cat").await?.is_none());
+        assert!(delete_user(&db, "cat").await.is_err());
+        assert!(set_password(&db, "cat", "meow".into()).await.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_init_admin_password() -> anyhow::Result<()> {
+        let db = connect("sqlite::memory:".parse()?).await?;
+        assert!(init_admin_with(&db, Some(String::new())).await.is_err());
+        assert!(init_admin_with(&db, Some(" \t".into())).await.is_err());
+        assert!(find_by_username(&db, ADMIN_USERNAME).await?.is_none());
+
+        init_admin_with(&db, Some("meow".into())).await?;
+        assert!(authenticate(&db, ADMIN_USERNAME, "meow".into())
+            .await?
+            .is_some());
+        Ok(())
+    }
+}